@@ -0,0 +1,143 @@
+use std::env::args;
+use tracing::debug;
+
+/// Options that take a value, e.g. `--some-option value` or `--some-option=value`.
+/// All other options are treated as boolean flags.
+const OPTIONS_WITH_VALUES: &[&str] = &["--backend", "--gen", "--route", "--connection-id", "--body", "--mutate", "--gen-cmd", "--interval-ms", "--run", "--max-runtime", "--replay-session", "--env-file", "--event-name", "--key", "--new-image", "--old-image"];
+
+/// The name of the cargo subcommand, as in `cargo lambda-debugger`.
+const SUBCOMMAND: &str = "lambda-debugger";
+
+/// Command line arguments of the emulator split into positional params and `--` options.
+pub(crate) struct CliArgs {
+    /// Params that are not options, e.g. the payload file name
+    pub positional: Vec<String>,
+    /// Options in the order they were given with their values, if any
+    options: Vec<(String, Option<String>)>,
+}
+
+impl CliArgs {
+    /// Reads the arguments of the current process, skipping the name of the executable
+    /// and the name of the cargo subcommand, if present.
+    pub fn from_env() -> Self {
+        debug!("Args: {}", args().collect::<Vec<String>>().join(" "));
+
+        Self::from_args(args())
+    }
+
+    /// Skips the name of the executable and the name of the cargo subcommand if it is the first param.
+    /// cargo passes the subcommand name as the first param regardless of how the executable is named or linked,
+    /// e.g. `/home/mx/.cargo/bin/cargo-lambda-debugger lambda-debugger payload.json` for `cargo lambda-debugger payload.json`.
+    fn from_args(args: impl Iterator<Item = String>) -> Self {
+        let mut params = args.skip(1).peekable();
+        params.next_if(|v| v == SUBCOMMAND);
+
+        Self::parse(params)
+    }
+
+    /// Splits the params into positional and options.
+    /// Only the options listed in OPTIONS_WITH_VALUES consume the next param as their value.
+    fn parse(params: impl Iterator<Item = String>) -> Self {
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+        let mut params = params.peekable();
+
+        while let Some(param) = params.next() {
+            if !param.starts_with("--") {
+                positional.push(param);
+                continue;
+            }
+
+            // --option=value
+            if let Some((name, value)) = param.split_once('=') {
+                options.push((name.to_string(), Some(value.to_string())));
+                continue;
+            }
+
+            // --option value
+            let value = if OPTIONS_WITH_VALUES.contains(&param.as_str()) {
+                params.next_if(|v| !v.starts_with("--"))
+            } else {
+                None
+            };
+            options.push((param, value));
+        }
+
+        Self { positional, options }
+    }
+
+    /// Returns the value of the option or None if the option is not present or has no value.
+    /// If the option is repeated, the last value wins.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    /// Returns all values of a repeated option in the order they were given.
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        self.options
+            .iter()
+            .filter(|(n, _)| n == name)
+            .filter_map(|(_, v)| v.as_deref())
+            .collect()
+    }
+
+    /// Returns true if the option is present, with or without a value.
+    pub fn has(&self, name: &str) -> bool {
+        self.options.iter().any(|(n, _)| n == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[&str]) -> CliArgs {
+        CliArgs::from_args(args.iter().map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn standalone_binary() {
+        let args = from_args(&["./target/debug/cargo-lambda-debugger", "payload.json", "--strict"]);
+        assert_eq!(args.positional, vec!["payload.json"]);
+        assert!(args.has("--strict"));
+    }
+
+    #[test]
+    fn cargo_subcommand() {
+        let args = from_args(&[
+            "/home/mx/.cargo/bin/cargo-lambda-debugger",
+            "lambda-debugger",
+            "--mutate",
+            "event.id=counter",
+            "payload.json",
+        ]);
+        assert_eq!(args.positional, vec!["payload.json"]);
+        assert_eq!(args.value("--mutate"), Some("event.id=counter"));
+    }
+
+    #[test]
+    fn cargo_subcommand_without_params() {
+        let args = from_args(&["/home/mx/.cargo/bin/cargo-lambda-debugger", "lambda-debugger"]);
+        assert!(args.positional.is_empty());
+    }
+
+    #[test]
+    fn renamed_binary() {
+        // a symlink with a different name invoked via cargo and directly
+        let args = from_args(&["/usr/local/bin/lde", "lambda-debugger", "payload.json"]);
+        assert_eq!(args.positional, vec!["payload.json"]);
+
+        let args = from_args(&["/usr/local/bin/lde", "payload.json"]);
+        assert_eq!(args.positional, vec!["payload.json"]);
+    }
+
+    #[test]
+    fn subcommand_name_is_stripped_only_once() {
+        let args = from_args(&["cargo-lambda-debugger", "lambda-debugger", "lambda-debugger"]);
+        assert_eq!(args.positional, vec!["lambda-debugger"]);
+    }
+}
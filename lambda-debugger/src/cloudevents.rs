@@ -0,0 +1,18 @@
+use serde_json::{json, Value};
+
+/// Wraps the payload into a [CloudEvents v1.0](https://github.com/cloudevents/spec/blob/v1.0.2/cloudevents/spec.md)
+/// envelope in structured JSON mode. The source tells where the payload came from, e.g. `file` or `sqs`.
+/// Payloads that are not valid JSON are passed in `data` as a string.
+pub(crate) fn wrap(payload: &str, source: &str) -> String {
+    let data = serde_json::from_str::<Value>(payload).unwrap_or_else(|_| Value::String(payload.to_owned()));
+
+    json!({
+        "specversion": "1.0",
+        "id": uuid::Uuid::new_v4().to_string(),
+        "source": format!("/lambda-debugger/{source}"),
+        "type": "lambda-debugger.event",
+        "datacontenttype": "application/json",
+        "data": data,
+    })
+    .to_string()
+}
@@ -1,16 +1,50 @@
-use crate::sqs::get_default_queues;
+use crate::args::CliArgs;
+use crate::codec::Encoding;
+use crate::generators::Generator;
+use crate::memory::MemoryBackend;
+use crate::mutate::Mutation;
+use crate::queues::{MessageSink, MessageSource};
+use crate::s3;
+use crate::schema::{self, load_schema};
+use crate::session;
+use crate::sqs::{check_permissions, get_default_queues, list_queues, SqsBackend};
+use crate::tls::load_tls_config;
+use crate::transform::Transform;
 use core::net::SocketAddrV4;
-use std::env::{args, var};
+use flate2::read::GzDecoder;
+use hyper::StatusCode;
+use jsonschema::Validator;
+use regex::Regex;
+use std::collections::HashMap;
+use std::env::VarError;
+use std::io::Read;
 use std::net::Ipv4Addr;
 use std::str::FromStr;
-use tracing::{debug, info, warn};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio_rustls::rustls::ServerConfig;
+use tracing::{info, warn};
 
 const REQUIRED_ENV_VARS: &str = "export AWS_LAMBDA_FUNCTION_VERSION=$LATEST && export AWS_LAMBDA_FUNCTION_MEMORY_SIZE=128 && export AWS_LAMBDA_FUNCTION_NAME=my-lambda && export AWS_LAMBDA_RUNTIME_API=127.0.0.1:9001";
 
+/// The payload file used if there is no payload file in the params and no queues.
+const DEFAULT_PAYLOAD_FILE: &str = "lambda-event.json";
+
+/// The env vars from `--env-file` that override the env vars inherited from the shell.
+/// They are kept apart from the process env because changing it is not safe while other threads may be reading it.
+static ENV_FILE_VARS: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+/// Contains compiled regex for `${VAR}` placeholders in the payload files.
+static ENV_VAR_REGEX: OnceLock<Regex> = OnceLock::new();
+
 /// Payloads come from a local file, responses are not sent anywhere
 pub(crate) struct LocalConfig {
-    /// Decoded payload from the local file. Can be anything as long as it's UTF-8
-    pub payload: String,
+    /// Decoded payloads from the local file. Can be anything as long as it's UTF-8.
+    /// JSON Lines files have one payload per line, all other files have a single payload.
+    pub payloads: Vec<String>,
+    /// A shell command that prints the payload to stdout, set with `--gen-cmd` param.
+    /// It runs on every invocation and its output is used instead of `payloads`.
+    pub command: Option<String>,
     /// File name from which the payload was read, as provided in the param
     pub file_name: String,
 }
@@ -24,17 +58,157 @@ pub(crate) struct RemoteConfig {
     pub response_queue_url: Option<String>,
 }
 
+/// Payloads come from an in-process queue via `POST /local/enqueue`,
+/// responses can be collected via `GET /local/dequeue`
+pub(crate) struct MemoryConfig {
+    /// Max number of messages in each of the request and response queues.
+    /// Set with LAMBDA_EMULATOR_MEMORY_QUEUE_SIZE env var.
+    pub capacity: usize,
+}
+
 /// A concrete type for either remote or local source of payloads
 pub(crate) enum PayloadSources {
     Local(LocalConfig),
     Remote(RemoteConfig),
+    Memory(MemoryConfig),
 }
 
-pub(crate) struct Config {
+/// The emulator settings.
+/// Use Config::from_env for the settings from the env vars and command line params
+/// or Config::local for a minimal config with a local payload file.
+pub struct Config {
     /// E.g. 127.0.0.1:9001
-    pub lambda_api_listener: SocketAddrV4,
+    pub(crate) lambda_api_listener: SocketAddrV4,
+    /// TLS settings for the runtime API listener from LAMBDA_EMULATOR_TLS_CERT and LAMBDA_EMULATOR_TLS_KEY env vars.
+    /// The listener uses plain HTTP if None.
+    pub(crate) tls: Option<Arc<ServerConfig>>,
     /// Source and destination of request and response payloads
-    pub sources: PayloadSources,
+    pub(crate) sources: PayloadSources,
+    /// Treat contract violations, e.g. schema validation failures, as errors.
+    /// Set with `--strict` param.
+    pub(crate) strict: bool,
+    /// Treat the lambda responses as Lambda function URL responses with a `statusCode` field.
+    /// Set with `--function-url` param.
+    pub(crate) function_url: bool,
+    /// Compiled JSON schema from the file in LAMBDA_EMULATOR_REQUEST_SCHEMA env var
+    /// to validate the events against before they are sent to the lambda.
+    pub(crate) request_schema: Option<Validator>,
+    /// A script that modifies the events before they are sent to the lambda,
+    /// from LAMBDA_EMULATOR_TRANSFORM_SCRIPT env var.
+    pub(crate) transform: Option<Transform>,
+    /// Compiled JSON schema from the file in LAMBDA_EMULATOR_RESPONSE_SCHEMA env var
+    /// to validate the lambda responses against.
+    pub(crate) response_schema: Option<Validator>,
+    /// An artificial delay before responding to the next invocation request
+    /// from LAMBDA_EMULATOR_INJECT_DELAY_MS env var.
+    pub(crate) inject_delay: Option<Duration>,
+    /// An artificial delay before sending the lambda response to the response queue
+    /// from LAMBDA_EMULATOR_RESPONSE_DELAY_MS env var.
+    pub(crate) response_delay: Option<Duration>,
+    /// A delay before serving the first invocation to let the lambda finish its initialization
+    /// from LAMBDA_EMULATOR_STARTUP_DELAY_MS env var.
+    pub(crate) startup_delay: Option<Duration>,
+    /// Time the lambda gets for each invocation, counted from when the invocation is served.
+    /// It replaces the deadline of the request, which may be in the past for old messages.
+    /// Set with LAMBDA_EMULATOR_DEADLINE_OVERRIDE_SECS env var.
+    pub(crate) deadline_override: Option<Duration>,
+    /// A pause before serving each local payload after the first one to space out the events.
+    /// Set with `--interval-ms` param.
+    pub(crate) interval: Option<Duration>,
+    /// The probability of a synthetic failure in response to a lambda request, from 0 to 1.
+    /// Set with LAMBDA_EMULATOR_ERROR_RATE env var.
+    pub(crate) error_rate: Option<f64>,
+    /// A seed for the fault injection randomizer from LAMBDA_EMULATOR_ERROR_SEED env var
+    /// to make the failures reproducible.
+    pub(crate) error_seed: Option<u64>,
+    /// Return the lambda errors in the body of the error responses and forward them to the response queue.
+    /// Set with LAMBDA_EMULATOR_ECHO_ERRORS env var.
+    pub(crate) echo_errors: bool,
+    /// JSON for `lambda-runtime-client-context` header from LAMBDA_EMULATOR_CLIENT_CONTEXT env var.
+    /// It is used when the request context has no client context, e.g. with local payloads.
+    pub(crate) client_context: Option<String>,
+    /// JSON for `lambda-runtime-cognito-identity` header from LAMBDA_EMULATOR_COGNITO_IDENTITY env var.
+    /// It is used when the request context has no identity, e.g. with local payloads.
+    pub(crate) cognito_identity: Option<String>,
+    /// The kind of synthetic event set with `--gen` param, if any.
+    pub(crate) generator: Option<Generator>,
+    /// How often to log a heartbeat while waiting for SQS messages, from LAMBDA_EMULATOR_HEARTBEAT_SECS env var.
+    /// Defaults to 5 min. Set to 0 to disable.
+    pub(crate) heartbeat_interval: Option<Duration>,
+    /// How many consecutive SQS receive failures are retried before the emulator exits with an error,
+    /// from LAMBDA_EMULATOR_MAX_RECEIVE_RETRIES env var. Unlimited by default.
+    pub(crate) max_receive_retries: Option<u32>,
+    /// How many responses that failed to send, e.g. because the response queue is throttled, are kept in memory
+    /// and retried in the background, from LAMBDA_EMULATOR_RESP_BUFFER_SIZE env var. Defaults to 100, 0 disables it.
+    pub(crate) response_buffer_size: usize,
+    /// How long to wait for the lambda to respond to a local or in-memory invocation before warning
+    /// that it may have crashed, from LAMBDA_EMULATOR_ORPHAN_TIMEOUT_SECS env var.
+    /// SQS invocations are checked when the message becomes visible in the queue again.
+    /// Defaults to 15 min, which is the max lambda run time. Set to 0 to disable the warnings.
+    pub(crate) orphan_timeout: Option<Duration>,
+    /// Fail the invocations the lambda has not responded to by their deadline, same as AWS does.
+    /// Set with LAMBDA_EMULATOR_ENFORCE_TIMEOUT env var.
+    pub(crate) enforce_timeout: bool,
+    /// Message attributes added to every message sent to the response queue,
+    /// from LAMBDA_EMULATOR_RESP_ATTRS env var, e.g. `env=local,source=debugger`.
+    pub(crate) response_attributes: Vec<(String, String)>,
+    /// The encoding of compressed responses, from LAMBDA_EMULATOR_ENCODING env var (`base58` or `base64url`).
+    /// Defaults to Base58. Use base64url for URL-safe consumers of the response queue.
+    pub(crate) encoding: Encoding,
+    /// The status of the response to the lambda posting its invocation response, 202 by default as per the spec.
+    /// Set to 200 with LAMBDA_EMULATOR_ACK_STATUS env var for runtime clients that expect it.
+    pub(crate) ack_status: StatusCode,
+    /// Block the next invocation after a lambda error to prevent an infinite loop of reruns.
+    /// Disabled with LAMBDA_EMULATOR_NO_BLOCK_ON_ERROR env var.
+    pub(crate) block_on_error: bool,
+    /// How many times a failed invocation is rerun straight away before the next invocation is blocked.
+    /// Set with LAMBDA_EMULATOR_MAX_RERUNS env var.
+    pub(crate) max_reruns: u32,
+    /// Fields of local payloads that get new values on every invocation, set with `--mutate` params.
+    pub(crate) mutations: Vec<Mutation>,
+    /// Send the whole SQS message with the event and the context to the lambda instead of just the event.
+    /// Set with `--pass-full-payload` param.
+    pub(crate) pass_full_payload: bool,
+    /// Wrap the events into CloudEvents envelopes before sending them to the lambda.
+    /// Set with `--cloudevents` param.
+    pub(crate) cloudevents: bool,
+    /// Print a machine-readable line to stdout when the emulator is ready to accept connections.
+    /// Set with `--ready-banner` param.
+    pub(crate) ready_banner: bool,
+    /// A response sent to the response queue for every request instead of waiting for the lambda
+    /// to test the proxy and the queues without a handler. Set with LAMBDA_EMULATOR_CANNED_RESPONSE env var.
+    pub(crate) canned_response: Option<String>,
+    /// A JSON pointer to the part of the event to log instead of the whole event, e.g. `/detail/requestParameters`.
+    /// Set with LAMBDA_EMULATOR_LOG_POINTER env var.
+    pub(crate) log_pointer: Option<String>,
+    /// Overrides the Sampled flag of the X-Ray trace header sent to the lambda to turn the X-Ray segments
+    /// of the handler on or off. Set with LAMBDA_EMULATOR_XRAY_SAMPLED env var to 0 or 1.
+    pub(crate) xray_sampled: Option<bool>,
+    /// A JSON Lines file to record the served events and the lambda responses and errors to for a later replay
+    /// with `--replay-session` param. Set with LAMBDA_EMULATOR_SESSION_FILE env var.
+    pub(crate) session_file: Option<String>,
+    /// A shell command to start the lambda with once the emulator is listening, e.g. `cargo run`.
+    /// Set with `--run` param.
+    pub(crate) run: Option<String>,
+    /// How long the emulator runs before shutting down regardless of activity, e.g. in CI.
+    /// Set with `--max-runtime` param.
+    pub(crate) max_runtime: Option<Duration>,
+    /// Shut down after the first invocation and exit with code 2 if the lambda posted an error.
+    /// Set with `--once` param.
+    pub(crate) once: bool,
+    /// Expose the emulator internals for debugging, e.g. extra headers for the lambda.
+    /// Set with LAMBDA_EMULATOR_DEBUG env var.
+    pub(crate) debug: bool,
+    /// Log the method, path and headers of every request from the lambda to diagnose runtime client issues.
+    /// Set with LAMBDA_EMULATOR_TRACE_HTTP env var.
+    pub(crate) trace_http: bool,
+    /// Log a REPORT line with the duration of every invocation, similar to the one logged by AWS.
+    /// Set with LAMBDA_EMULATOR_REPORT_LINE env var.
+    pub(crate) report_line: bool,
+    /// The memory size of the lambda in MB for the REPORT line, the context of the events from the memory queue
+    /// and the lambda started with `--run`, from AWS_LAMBDA_FUNCTION_MEMORY_SIZE env var.
+    /// Defaults to 128, same as in the env vars printed for the lambda.
+    pub(crate) memory_size: u32,
 }
 
 impl Config {
@@ -42,8 +216,43 @@ impl Config {
     /// Uses default values where possible.
     /// Panics if the required environment variables are not set.
     pub async fn from_env() -> Self {
+        let args = CliArgs::from_env();
+
+        // cargo help lambda-debugger is equivalent to `/home/mx/.cargo/bin/cargo-lambda-debugger lambda-debugger --help`
+        if args.has("--help") {
+            print_help();
+            std::process::exit(0);
+        }
+
+        // a diagnostic command to check the AWS setup without starting the emulator
+        if is_subcommand(&args, "list-queues") {
+            list_queues().await;
+            std::process::exit(0);
+        }
+
+        // a quick check of the payload files without starting the emulator, e.g. in a pre-commit hook
+        if is_subcommand(&args, "validate") {
+            let is_valid = validate_payload_files(&args.positional[1..], args.has("--jsonl"));
+            std::process::exit(if is_valid { 0 } else { 1 });
+        }
+
+        if args.has("--check-permissions") {
+            check_permissions(args.has("--probe-receive")).await;
+            std::process::exit(0);
+        }
+
+        // the env file is re-read on every config reload, unlike the env vars inherited from the shell
+        if let Some(env_file) = args.value("--env-file") {
+            load_env_file(env_file);
+        }
+
         // 127.0.0.1:9001 is the default endpoint used on AWS
-        let listener_ip_str = var("AWS_LAMBDA_RUNTIME_API").unwrap_or_else(|_e| "127.0.0.1:9001".to_string());
+        // PORT env var is a fallback for environments that assign the port to the app, e.g. PaaS
+        let listener_ip_str = match (var("AWS_LAMBDA_RUNTIME_API"), var("PORT")) {
+            (Ok(v), _) => v,
+            (Err(_), Ok(port)) => format!("127.0.0.1:{port}"),
+            _ => "127.0.0.1:9001".to_string(),
+        };
 
         let lambda_api_listener = match listener_ip_str.split_once(':') {
             Some((ip, port)) => {
@@ -51,46 +260,231 @@ impl Config {
                     "Invalid IP address in AWS_LAMBDA_RUNTIME_API env var. Must be a valid IP4, e.g. 127.0.0.1",
                 );
                 let listener_port = port.parse::<u16>().expect(
-                    "Invalid port number in AWS_LAMBDA_RUNTIME_API env var. Must be a valid port number, e.g. 9001",
+                    "Invalid port number in AWS_LAMBDA_RUNTIME_API or PORT env var. Must be a valid port number, e.g. 9001",
                 );
                 SocketAddrV4::new(listener_ip, listener_port)
             }
             None => SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 9001),
         };
 
+        let generator = Generator::from_args(&args);
+
+        // the in-memory backend replaces both local and remote sources
+        let memory_config = match args.value("--backend") {
+            Some("memory") => {
+                if !args.positional.is_empty() || generator.is_some() {
+                    panic!("A payload file cannot be used with `--backend memory`. Send the payload via POST /local/enqueue instead.");
+                }
+                Some(get_memory_config())
+            }
+            Some("sqs") | None => None,
+            Some(v) => panic!("Invalid --backend value: {v}. Must be `sqs` or `memory`."),
+        };
+
         // attempt to extract payload from a local file if the file name is provided in the command line arguments
         // alternatively try to find remote queues
         // exit if no sources are set
-        let sources = match get_local_payload() {
-            Some(local_config) => {
-                info!(
-                    "Listening on http://{}\n- payload from: {}\n",
-                    lambda_api_listener, local_config.file_name
-                );
+        let sources = if let Some(memory_config) = memory_config {
+            info!(
+                "Listening on http://{}\n- backend: memory, capacity {}\n- send events:   POST http://{0}/local/enqueue\n- get responses: GET http://{0}/local/dequeue\n",
+                lambda_api_listener, memory_config.capacity
+            );
 
-                PayloadSources::Local(local_config)
-            }
-            None => match get_queues().await {
-                Some(remote_config) => {
+            PayloadSources::Memory(memory_config)
+        } else {
+            match get_local_payload(&args, generator).await {
+                Some(local_config) => {
                     info!(
-                        "Listening on http://{}\n- request queue:  {}\n- response queue: {}\n",
+                        "Listening on http://{}\n- payload from: {} ({} event(s))\n",
                         lambda_api_listener,
-                        remote_config.request_queue_url,
-                        remote_config.response_queue_url.clone().unwrap_or_else(String::new),
+                        local_config.file_name,
+                        local_config.payloads.len()
                     );
 
-                    PayloadSources::Remote(remote_config)
-                }
-                None => {
-                    panic!("No payload source is set.\nAdd payload file name as a param for local debugging or create request / response queues for remote debugging.\nSee ReadMe for more info.");
+                    PayloadSources::Local(local_config)
                 }
-            },
+                None => match get_queues().await {
+                    Some(remote_config) => {
+                        info!(
+                            "Listening on http://{}\n- request queue:  {}\n- response queue: {}\n",
+                            lambda_api_listener,
+                            remote_config.request_queue_url,
+                            remote_config.response_queue_url.clone().unwrap_or_else(String::new),
+                        );
+
+                        PayloadSources::Remote(remote_config)
+                    }
+                    None => match get_default_payload(&args).await {
+                        Some(local_config) => {
+                            info!(
+                                "Listening on http://{}\n- payload from: {} ({} event(s))\n",
+                                lambda_api_listener,
+                                local_config.file_name,
+                                local_config.payloads.len()
+                            );
+
+                            PayloadSources::Local(local_config)
+                        }
+                        None => {
+                            panic!("No payload source is set.\nAdd payload file name as a param or {DEFAULT_PAYLOAD_FILE} file for local debugging or create request / response queues for remote debugging.\nSee ReadMe for more info.");
+                        }
+                    },
+                },
+            }
         };
-        warn!("Add required env vars and start the lambda:\n{}\n", REQUIRED_ENV_VARS);
+        let canned_response = get_canned_response();
+        let run = args.value("--run").map(str::to_owned);
+        match (&canned_response, &sources) {
+            (Some(_), _) if run.is_some() => {
+                panic!("LAMBDA_EMULATOR_CANNED_RESPONSE cannot be used with `--run`. The canned response replaces the lambda.")
+            }
+            (Some(_), PayloadSources::Local(_)) => {
+                panic!("LAMBDA_EMULATOR_CANNED_RESPONSE cannot be used with a local payload. It needs a request queue.")
+            }
+            (Some(_), _) => info!("Answering all requests with the canned response. Do not start the lambda.\n"),
+            (None, _) if run.is_some() || !needs_env_var_reminder() => {}
+            (None, _) => warn!("Add required env vars and start the lambda:\n{}\n", REQUIRED_ENV_VARS),
+        }
 
         Self {
             lambda_api_listener,
+            tls: load_tls_config(),
             sources,
+            strict: args.has("--strict"),
+            function_url: args.has("--function-url"),
+            request_schema: load_schema("LAMBDA_EMULATOR_REQUEST_SCHEMA"),
+            transform: Transform::from_env(),
+            response_schema: load_schema("LAMBDA_EMULATOR_RESPONSE_SCHEMA"),
+            inject_delay: parse_env_var("LAMBDA_EMULATOR_INJECT_DELAY_MS").map(Duration::from_millis),
+            startup_delay: parse_env_var("LAMBDA_EMULATOR_STARTUP_DELAY_MS").map(Duration::from_millis),
+            response_delay: parse_env_var("LAMBDA_EMULATOR_RESPONSE_DELAY_MS").map(Duration::from_millis),
+            deadline_override: parse_env_var("LAMBDA_EMULATOR_DEADLINE_OVERRIDE_SECS").map(Duration::from_secs),
+            interval: args.value("--interval-ms").map(|v| match v.parse::<u64>() {
+                Ok(v) => Duration::from_millis(v),
+                Err(e) => panic!("Invalid --interval-ms value: {v}. Must be a number of milliseconds, e.g. 500\n{e}"),
+            }),
+            error_rate: get_error_rate(),
+            error_seed: parse_env_var("LAMBDA_EMULATOR_ERROR_SEED"),
+            echo_errors: is_env_var_set("LAMBDA_EMULATOR_ECHO_ERRORS"),
+            client_context: get_json_env_var("LAMBDA_EMULATOR_CLIENT_CONTEXT"),
+            cognito_identity: get_json_env_var("LAMBDA_EMULATOR_COGNITO_IDENTITY"),
+            generator,
+            heartbeat_interval: match parse_env_var("LAMBDA_EMULATOR_HEARTBEAT_SECS").unwrap_or(300) {
+                0 => None,
+                v => Some(Duration::from_secs(v)),
+            },
+            max_receive_retries: parse_env_var("LAMBDA_EMULATOR_MAX_RECEIVE_RETRIES"),
+            response_buffer_size: parse_env_var("LAMBDA_EMULATOR_RESP_BUFFER_SIZE").unwrap_or(100),
+            orphan_timeout: match parse_env_var("LAMBDA_EMULATOR_ORPHAN_TIMEOUT_SECS").unwrap_or(900) {
+                0 => None,
+                v => Some(Duration::from_secs(v)),
+            },
+            enforce_timeout: is_env_var_set("LAMBDA_EMULATOR_ENFORCE_TIMEOUT"),
+            response_attributes: get_response_attributes(),
+            encoding: get_encoding(),
+            ack_status: get_ack_status(),
+            block_on_error: !is_env_var_set("LAMBDA_EMULATOR_NO_BLOCK_ON_ERROR"),
+            max_reruns: parse_env_var("LAMBDA_EMULATOR_MAX_RERUNS").unwrap_or(0),
+            mutations: args.values("--mutate").into_iter().map(Mutation::parse).collect(),
+            pass_full_payload: args.has("--pass-full-payload"),
+            cloudevents: args.has("--cloudevents"),
+            ready_banner: args.has("--ready-banner"),
+            canned_response,
+            log_pointer: get_log_pointer(),
+            xray_sampled: get_xray_sampled(),
+            session_file: var("LAMBDA_EMULATOR_SESSION_FILE")
+                .ok()
+                .filter(|v| !v.trim().is_empty()),
+            run,
+            max_runtime: args.value("--max-runtime").map(|v| match parse_duration(v) {
+                Some(v) => v,
+                None => panic!("Invalid --max-runtime value: {v}. Must be a duration, e.g. 90s, 5m or 1h"),
+            }),
+            once: args.has("--once"),
+            debug: is_env_var_set("LAMBDA_EMULATOR_DEBUG"),
+            trace_http: is_env_var_set("LAMBDA_EMULATOR_TRACE_HTTP"),
+            report_line: is_env_var_set("LAMBDA_EMULATOR_REPORT_LINE"),
+            memory_size: parse_env_var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE").unwrap_or(128),
+        }
+    }
+
+    /// Creates a config for serving the events from the local payload file, e.g. in tests.
+    /// The emulator listens on a random port of 127.0.0.1, see Emulator::addr.
+    /// All other settings are off. Env vars and command line params are ignored.
+    /// Panics if the payload cannot be read.
+    pub fn local(payload_file: &str) -> Self {
+        Self {
+            lambda_api_listener: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+            tls: None,
+            sources: PayloadSources::Local(read_local_payload(payload_file, false)),
+            strict: false,
+            function_url: false,
+            request_schema: None,
+            transform: None,
+            response_schema: None,
+            inject_delay: None,
+            startup_delay: None,
+            response_delay: None,
+            deadline_override: None,
+            interval: None,
+            error_rate: None,
+            error_seed: None,
+            echo_errors: false,
+            client_context: None,
+            cognito_identity: None,
+            generator: None,
+            heartbeat_interval: None,
+            max_receive_retries: None,
+            response_buffer_size: 0,
+            orphan_timeout: None,
+            enforce_timeout: false,
+            response_attributes: Vec::new(),
+            encoding: Encoding::Base58,
+            ack_status: StatusCode::ACCEPTED,
+            block_on_error: true,
+            max_reruns: 0,
+            mutations: Vec::new(),
+            pass_full_payload: false,
+            cloudevents: false,
+            ready_banner: false,
+            canned_response: None,
+            log_pointer: None,
+            xray_sampled: None,
+            session_file: None,
+            run: None,
+            max_runtime: None,
+            once: false,
+            debug: false,
+            trace_http: false,
+            report_line: false,
+            memory_size: 128,
+        }
+    }
+
+    /// Returns the name of the payload source for logging, e.g. `local`.
+    pub(crate) fn mode(&self) -> &'static str {
+        match &self.sources {
+            PayloadSources::Local(_) => "local",
+            PayloadSources::Remote(_) => "remote",
+            PayloadSources::Memory(_) => "memory",
+        }
+    }
+
+    /// Returns the queue the payloads come from or None for local payloads.
+    pub(crate) fn message_source(&self) -> Option<&'static dyn MessageSource> {
+        match &self.sources {
+            PayloadSources::Local(_) => None,
+            PayloadSources::Remote(_) => Some(&SqsBackend),
+            PayloadSources::Memory(_) => Some(&MemoryBackend),
+        }
+    }
+
+    /// Returns the queue the responses go to or None for local payloads.
+    pub(crate) fn message_sink(&self) -> Option<&'static dyn MessageSink> {
+        match &self.sources {
+            PayloadSources::Local(_) => None,
+            PayloadSources::Remote(_) => Some(&SqsBackend),
+            PayloadSources::Memory(_) => Some(&MemoryBackend),
         }
     }
 
@@ -105,6 +499,263 @@ impl Config {
     }
 }
 
+/// Returns the value of the env var parsed into the required type or None if the env var is not set or is empty.
+/// Panics if the value cannot be parsed.
+pub(crate) fn parse_env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = var(name).ok().filter(|v| !v.trim().is_empty())?;
+
+    match value.trim().parse::<T>() {
+        Ok(v) => Some(v),
+        Err(_) => panic!("Invalid value in {name} env var: {value}"),
+    }
+}
+
+/// Returns the value of the env var from `--env-file`, if the file has it, or from the process env otherwise.
+/// All emulator settings are read via this function so that they can be changed in the env file at runtime.
+pub(crate) fn var(name: &str) -> Result<String, VarError> {
+    let env_file_value = match ENV_FILE_VARS.lock() {
+        Ok(v) => v.as_ref().and_then(|vars| vars.get(name).cloned()),
+        Err(e) => panic!("ENV_FILE_VARS mutex is poisoned. It's a bug. {:?}", e),
+    };
+
+    match env_file_value {
+        Some(v) => Ok(v),
+        None => std::env::var(name),
+    }
+}
+
+/// Returns a copy of the env vars loaded from `--env-file`, e.g. to pass them onto the lambda process.
+pub(crate) fn env_file_vars() -> HashMap<String, String> {
+    match ENV_FILE_VARS.lock() {
+        Ok(v) => v.clone().unwrap_or_default(),
+        Err(e) => panic!("ENV_FILE_VARS mutex is poisoned. It's a bug. {:?}", e),
+    }
+}
+
+/// Reads the env vars from the file with `NAME=value` lines. They override the values inherited from the shell.
+/// Blank lines and lines starting with `#` are skipped. The values can be wrapped in single or double quotes.
+/// The vars loaded from the previous version of the file are replaced, so the removed vars get their shell values back.
+/// Panics if the file cannot be read or has invalid lines.
+fn load_env_file(env_file: &str) {
+    let contents = match std::fs::read_to_string(env_file) {
+        Ok(v) => v,
+        Err(e) => panic!("Failed to read env file {env_file}\n{:?}", e),
+    };
+
+    let vars = parse_env_file(&contents, env_file);
+    info!("Loaded {} env vars from {env_file}", vars.len());
+
+    match ENV_FILE_VARS.lock() {
+        Ok(mut v) => *v = Some(vars),
+        Err(e) => panic!("ENV_FILE_VARS mutex is poisoned. It's a bug. {:?}", e),
+    }
+}
+
+/// Parses the contents of the env file into a map of env var names and values.
+/// Panics on lines without `=`.
+fn parse_env_file(contents: &str, env_file: &str) -> HashMap<String, String> {
+    let mut vars = HashMap::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            panic!(
+                "Invalid line {} in env file {env_file}: {line}. Must be NAME=value",
+                idx + 1
+            );
+        };
+
+        let value = value.trim();
+        let value = match (value.chars().next(), value.chars().last()) {
+            (Some(first @ ('"' | '\'')), Some(last)) if value.len() > 1 && first == last => &value[1..value.len() - 1],
+            _ => value,
+        };
+
+        vars.insert(name.trim().to_owned(), value.to_owned());
+    }
+
+    vars
+}
+
+/// Returns the JSON object from the env var, if set, as compact single-line JSON that is valid in an HTTP header.
+/// Panics if the value is not a JSON object to catch typos before the lambda sees them.
+fn get_json_env_var(name: &str) -> Option<String> {
+    let value = var(name).ok().filter(|v| !v.trim().is_empty())?;
+
+    match serde_json::from_str::<serde_json::Value>(&value) {
+        Ok(v) if v.is_object() => Some(v.to_string()),
+        Ok(_) => panic!("Invalid value in {name} env var: {value}. Must be a JSON object."),
+        Err(e) => panic!("Invalid JSON in {name} env var: {value}\n{e}"),
+    }
+}
+
+/// Returns false if the reminder about the env vars the lambda needs should not be printed:
+/// if LAMBDA_EMULATOR_QUIET env var is set or the env vars are already set in this shell.
+fn needs_env_var_reminder() -> bool {
+    if is_env_var_set("LAMBDA_EMULATOR_QUIET") {
+        return false;
+    }
+
+    ![
+        "AWS_LAMBDA_FUNCTION_VERSION",
+        "AWS_LAMBDA_FUNCTION_MEMORY_SIZE",
+        "AWS_LAMBDA_FUNCTION_NAME",
+        "AWS_LAMBDA_RUNTIME_API",
+    ]
+    .iter()
+    .all(|v| var(v).is_ok())
+}
+
+/// Returns the canned response from LAMBDA_EMULATOR_CANNED_RESPONSE env var.
+/// The value is either the response itself or `@` followed by the name of the file with the response.
+/// Panics if the file cannot be read.
+fn get_canned_response() -> Option<String> {
+    let value = var("LAMBDA_EMULATOR_CANNED_RESPONSE")
+        .ok()
+        .filter(|v| !v.trim().is_empty())?;
+
+    match value.strip_prefix('@') {
+        Some(file_name) => match std::fs::read_to_string(file_name) {
+            Ok(v) => Some(v),
+            Err(e) => panic!(
+                "Failed to read the canned response from {file_name} (LAMBDA_EMULATOR_CANNED_RESPONSE)\n{:?}",
+                e
+            ),
+        },
+        None => Some(value),
+    }
+}
+
+/// Returns the JSON pointer from LAMBDA_EMULATOR_LOG_POINTER env var.
+/// Panics if the value is not a valid JSON pointer.
+fn get_log_pointer() -> Option<String> {
+    let pointer = var("LAMBDA_EMULATOR_LOG_POINTER")
+        .ok()
+        .filter(|v| !v.trim().is_empty())?;
+
+    if !pointer.starts_with('/') {
+        panic!("Invalid value in LAMBDA_EMULATOR_LOG_POINTER env var: {pointer}. Must be a JSON pointer, e.g. /detail/requestParameters");
+    }
+
+    Some(pointer)
+}
+
+/// Returns the in-memory backend settings from the env vars or defaults.
+pub(crate) fn get_memory_config() -> MemoryConfig {
+    let capacity = parse_env_var("LAMBDA_EMULATOR_MEMORY_QUEUE_SIZE").unwrap_or(100);
+
+    if capacity == 0 {
+        panic!("Invalid value in LAMBDA_EMULATOR_MEMORY_QUEUE_SIZE env var: 0. Must be a positive number, e.g. 100");
+    }
+
+    MemoryConfig { capacity }
+}
+
+/// Parses a duration with `ms`, `s`, `m` or `h` suffix, e.g. `500ms`, `90s`, `5m` or `1h`.
+/// A number without a suffix is in seconds. Returns None if the value is invalid.
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit_ms) = if let Some(v) = value.strip_suffix("ms") {
+        (v, 1)
+    } else if let Some(v) = value.strip_suffix('s') {
+        (v, 1000)
+    } else if let Some(v) = value.strip_suffix('m') {
+        (v, 60_000)
+    } else if let Some(v) = value.strip_suffix('h') {
+        (v, 3_600_000)
+    } else {
+        (value, 1000)
+    };
+
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()?
+        .checked_mul(unit_ms)
+        .map(Duration::from_millis)
+}
+
+/// Returns true if the env var is set to anything other than an empty string, `0` or `false`.
+fn is_env_var_set(name: &str) -> bool {
+    match var(name) {
+        Ok(v) => !matches!(v.trim().to_lowercase().as_str(), "" | "0" | "false"),
+        Err(_) => false,
+    }
+}
+
+/// Returns the value of the X-Ray Sampled flag from LAMBDA_EMULATOR_XRAY_SAMPLED env var.
+/// Panics if the value is not 0 or 1.
+fn get_xray_sampled() -> Option<bool> {
+    match parse_env_var::<u8>("LAMBDA_EMULATOR_XRAY_SAMPLED")? {
+        0 => Some(false),
+        1 => Some(true),
+        v => panic!("Invalid value in LAMBDA_EMULATOR_XRAY_SAMPLED env var: {v}. Must be 0 or 1."),
+    }
+}
+
+/// Returns the fault injection rate from LAMBDA_EMULATOR_ERROR_RATE env var.
+/// Panics if the value is not a number between 0 and 1.
+fn get_error_rate() -> Option<f64> {
+    let error_rate = parse_env_var::<f64>("LAMBDA_EMULATOR_ERROR_RATE")?;
+
+    if !(0.0..=1.0).contains(&error_rate) {
+        panic!("Invalid value in LAMBDA_EMULATOR_ERROR_RATE env var: {error_rate}. Must be between 0 and 1, e.g. 0.1");
+    }
+
+    info!("Fault injection rate: {error_rate}");
+
+    Some(error_rate)
+}
+
+/// Returns the message attributes for the response queue from LAMBDA_EMULATOR_RESP_ATTRS env var
+/// in `key1=val1,key2=val2` format. Malformed entries are skipped with a warning.
+fn get_response_attributes() -> Vec<(String, String)> {
+    let Ok(attributes) = var("LAMBDA_EMULATOR_RESP_ATTRS") else {
+        return Vec::new();
+    };
+
+    attributes
+        .split(',')
+        .filter(|v| !v.trim().is_empty())
+        .filter_map(|entry| match entry.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => {
+                Some((key.trim().to_owned(), value.trim().to_owned()))
+            }
+            _ => {
+                warn!("Invalid entry in LAMBDA_EMULATOR_RESP_ATTRS env var: {entry}. Expected `key=value`. Skipped.");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns the status for acknowledging lambda responses from LAMBDA_EMULATOR_ACK_STATUS env var or 202 by default.
+/// Panics if the status is not 200 or 202.
+fn get_ack_status() -> StatusCode {
+    match parse_env_var::<u16>("LAMBDA_EMULATOR_ACK_STATUS") {
+        None | Some(202) => StatusCode::ACCEPTED,
+        Some(200) => StatusCode::OK,
+        Some(v) => panic!("Invalid value in LAMBDA_EMULATOR_ACK_STATUS env var: {v}. Must be 200 or 202."),
+    }
+}
+
+/// Reads the encoding of compressed responses from LAMBDA_EMULATOR_ENCODING env var.
+/// Panics on unknown values because the proxy would not be able to decode the responses.
+fn get_encoding() -> Encoding {
+    match var("LAMBDA_EMULATOR_ENCODING") {
+        Err(_) => Encoding::Base58,
+        Ok(v) => match v.trim().to_lowercase().as_str() {
+            "" | "base58" => Encoding::Base58,
+            "base64url" => Encoding::Base64Url,
+            _ => panic!("Invalid value in LAMBDA_EMULATOR_ENCODING env var: {v}. Must be base58 or base64url."),
+        },
+    }
+}
+
 /// Returns URLs of the request and response queues, if they exist.
 /// Reads values from the environment variables or uses the defaults.
 /// Does not panic.
@@ -145,58 +796,492 @@ async fn get_queues() -> Option<RemoteConfig> {
 
 /// Extracts the payload from a local file if the file name is provided in the command line arguments.
 /// Panics if the payload cannot be read.
-fn get_local_payload() -> Option<LocalConfig> {
-    // the number of arguments depends on if this is a cargo command or a standalone executable
-    // calculate where the params of the command are located inside the argument collection
-    let param_idx = args().next().map_or_else(
-        || 0, // this an impossible scenario because the very first argument is always the name of the executable
-        |v| {
-            if v.ends_with(
-                &args()
-                    .nth(1)
-                    .map_or_else(|| "###".to_string(), |v| format!("cargo-{v}")),
-            ) {
-                2 // invoked as a cargo command: `/home/mx/.cargo/bin/cargo-lambda-debugger lambda-debugger`
-            } else {
-                1 // invoked as a standalone binary: `/home/mx/projects/gh-forks/lambda-runtime-emulator/target/debug/cargo-lambda-debugger`
-            }
-        },
-    );
-    debug!(
-        "Param: {param_idx}, args: {}",
-        std::env::args().collect::<Vec<String>>().join(" ")
-    );
+/// A synthetic event from the generator is used instead of the file, if `--gen` param is present.
+/// The output of the command is used instead of the file, if `--gen-cmd` param is present.
+/// The events recorded in a session file are used instead of the file, if `--replay-session` param is present.
+/// `${VAR}` placeholders in the payload file are replaced with the env var values, if `--expand-env` param is present.
+/// Payloads with `s3://` URLs instead of file names are downloaded from S3.
+async fn get_local_payload(args: &CliArgs, generator: Option<Generator>) -> Option<LocalConfig> {
+    if let Some(generator) = generator {
+        if !args.positional.is_empty() {
+            panic!("A payload file cannot be used with `--gen`. Use one or the other.");
+        }
+
+        return Some(LocalConfig {
+            payloads: vec![generator.event(args)],
+            command: None,
+            file_name: format!("generated {generator:?} event"),
+        });
+    }
+
+    if let Some(command) = args.value("--gen-cmd") {
+        if !args.positional.is_empty() {
+            panic!("A payload file cannot be used with `--gen-cmd`. Use one or the other.");
+        }
+
+        return Some(LocalConfig {
+            payloads: Vec::new(),
+            command: Some(command.to_owned()),
+            file_name: format!("command `{command}`"),
+        });
+    }
+
+    if let Some(session_file) = args.value("--replay-session") {
+        if !args.positional.is_empty() {
+            panic!("A payload file cannot be used with `--replay-session`. Use one or the other.");
+        }
+
+        return Some(LocalConfig {
+            payloads: session::read_events(session_file),
+            command: None,
+            file_name: format!("session {session_file}"),
+        });
+    }
 
     // attempt to extract payload from a local file if the file name is provided in the command line arguments
-    if let Some(payload_file) = args().nth(param_idx) {
-        // cargo help lambda-debugger is equivalent to `/home/mx/.cargo/bin/cargo-lambda-debugger lambda-debugger --help`
-        if &payload_file == "--help" {
-            println!("AWS Lambda environment emulator for local and remote debugging.");
-            println!("1. run `cargo lambda-debugger`");
-            println!("2. copy the env vars printed by the emulator");
-            println!("3. set the env vars in a separate terminal and start your lambda there with `cargo run`");
-            println!();
-            println!("With local payload: cargo lambda-debugger [payload_file], e.g. lambda_payload.json");
-            println!("With payload from AWS: cargo lambda-debugger");
-            println!();
-            println!("See https://github.com/rimutaka/lambda-debugger-runtime-emulator for more info.");
+    let payload_file = args.positional.first()?;
 
-            std::process::exit(0);
+    Some(read_payload_file(args, payload_file).await)
+}
+
+/// Returns the payload from the conventional file in the current directory, if it exists.
+/// The file name is `lambda-event.json` unless it is set in LAMBDA_EMULATOR_DEFAULT_PAYLOAD env var.
+/// It is only used if there is no payload file in the params and no queues.
+async fn get_default_payload(args: &CliArgs) -> Option<LocalConfig> {
+    let payload_file = var("LAMBDA_EMULATOR_DEFAULT_PAYLOAD")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAYLOAD_FILE.to_owned());
+
+    if !std::path::Path::new(&resolve_payload_path(&payload_file)).is_file() {
+        return None;
+    }
+
+    info!("No payload file in the params and no queues found. Using {payload_file} as the payload.");
+
+    Some(read_payload_file(args, &payload_file).await)
+}
+
+/// Reads the payloads from the local or S3 file and expands the env vars in them if `--expand-env` is present.
+/// Panics if the payload cannot be read.
+async fn read_payload_file(args: &CliArgs, payload_file: &str) -> LocalConfig {
+    let jsonl = args.has("--jsonl");
+
+    let mut local_config = if payload_file.starts_with("s3://") {
+        let payload = decode_payload(s3::get_payload(payload_file).await, payload_file);
+        to_local_config(payload, payload_file, jsonl)
+    } else {
+        read_local_payload(&resolve_payload_path(payload_file), jsonl)
+    };
+
+    if args.has("--expand-env") {
+        let strict = args.has("--strict-env");
+        local_config.payloads = local_config
+            .payloads
+            .iter()
+            .map(|v| expand_env_vars(v, strict))
+            .collect();
+    }
+
+    local_config
+}
+
+/// Replaces `${VAR}` placeholders in the payload with the values of the env vars.
+/// The values are escaped inside JSON strings, e.g. `"${PATH}"`, and inserted as-is elsewhere,
+/// e.g. `{"count": ${COUNT}}`, so that the env var can hold a number or a JSON fragment.
+/// Undefined env vars are left as-is with a warning or cause a panic if `strict` is true.
+fn expand_env_vars(payload: &str, strict: bool) -> String {
+    let regex = ENV_VAR_REGEX
+        .get_or_init(|| Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("Invalid env var regex. It's a bug."));
+
+    let mut expanded = String::with_capacity(payload.len());
+    let mut last_end = 0;
+    let mut in_string = false;
+    let mut is_escaped = false;
+
+    for caps in regex.captures_iter(payload) {
+        let placeholder = caps.get(0).expect("Regex match without a placeholder. It's a bug.");
+
+        // the placeholder itself has no quotes or backslashes, so it does not change the state
+        for c in payload[last_end..placeholder.start()].chars() {
+            match c {
+                _ if is_escaped => is_escaped = false,
+                '\\' if in_string => is_escaped = true,
+                '"' => in_string = !in_string,
+                _ => {}
+            }
         }
+        expanded.push_str(&payload[last_end..placeholder.start()]);
+        last_end = placeholder.end();
 
-        // read the payload from the file
-        match std::fs::read_to_string(payload_file.clone()) {
-            Ok(payload) => Some(LocalConfig {
-                payload,
-                file_name: payload_file,
-            }),
+        let value = match var(&caps[1]) {
+            Ok(v) => v,
+            Err(_) if strict => panic!("Undefined env var in the payload: {}", placeholder.as_str()),
+            Err(_) => {
+                warn!(
+                    "Undefined env var in the payload: {}. The placeholder is left as-is.",
+                    placeholder.as_str()
+                );
+                expanded.push_str(placeholder.as_str());
+                continue;
+            }
+        };
+
+        if in_string {
+            // a JSON string of the value without the surrounding quotes
+            let escaped = serde_json::to_string(&value).expect("Failed to escape a string. It's a bug.");
+            expanded.push_str(&escaped[1..escaped.len() - 1]);
+        } else {
+            expanded.push_str(&value);
+        }
+    }
+
+    expanded.push_str(&payload[last_end..]);
+
+    expanded
+}
+
+/// Resolves a relative payload path against LAMBDA_EMULATOR_PAYLOAD_DIR, if set.
+/// It helps when the emulator is launched by an IDE from a directory other than the project root.
+/// Absolute paths and S3 URLs are returned as-is.
+fn resolve_payload_path(payload_file: &str) -> String {
+    let path = std::path::Path::new(payload_file);
+    if path.is_absolute() || payload_file.starts_with("s3://") {
+        return payload_file.to_owned();
+    }
+
+    match var("LAMBDA_EMULATOR_PAYLOAD_DIR") {
+        Ok(dir) if !dir.trim().is_empty() => std::path::Path::new(dir.trim())
+            .join(path)
+            .to_string_lossy()
+            .into_owned(),
+        _ => payload_file.to_owned(),
+    }
+}
+
+/// Reads the payloads from the file. JSON Lines files are split into separate payloads.
+/// Panics if the payload cannot be read.
+fn read_local_payload(payload_file: &str, jsonl: bool) -> LocalConfig {
+    // a mistyped path to a directory results in a confusing IO error
+    if std::path::Path::new(payload_file).is_dir() {
+        panic!("Expected a payload file, got a directory: {payload_file}");
+    }
+
+    // read the payload from the file
+    match std::fs::read(payload_file) {
+        Ok(payload) => to_local_config(decode_payload(payload, payload_file), payload_file, jsonl),
+
+        // there is no point proceeding if the payload cannot be read
+        Err(e) => {
+            panic!("Failed to read payload from {}\n{:?}", payload_file, e)
+        }
+    }
+}
+
+/// Converts the contents of the payload file into a string, decompressing gzipped files first,
+/// e.g. `event.json.gz`. Gzipped files are detected by their magic bytes, not by the extension.
+/// Panics if the file cannot be decompressed or is not UTF-8.
+fn decode_payload(contents: Vec<u8>, payload_file: &str) -> String {
+    match try_decode_payload(contents) {
+        Ok(v) => v,
+        Err(e) => panic!("{e} in {payload_file}"),
+    }
+}
+
+/// Same as `decode_payload`, but returns the error instead of panicking.
+fn try_decode_payload(contents: Vec<u8>) -> Result<String, String> {
+    let contents = if contents.starts_with(&[0x1f, 0x8b]) {
+        let mut decompressed = Vec::new();
+        if let Err(e) = GzDecoder::new(contents.as_slice()).read_to_end(&mut decompressed) {
+            return Err(format!("Failed to decompress gzipped payload: {e}"));
+        }
+        info!("Decompressed {}B of gzipped payload", decompressed.len());
+        decompressed
+    } else {
+        contents
+    };
+
+    String::from_utf8(contents).map_err(|e| format!("Non-UTF-8 payload: {e}"))
+}
+
+/// Returns true if the payload file has one payload per line, as per the extension or `--jsonl` param.
+fn is_json_lines(payload_file: &str, jsonl: bool) -> bool {
+    jsonl || payload_file.ends_with(".jsonl") || payload_file.ends_with(".jsonl.gz")
+}
 
-            // there is no point proceeding if the payload cannot be read
+/// Returns true if the first positional param is the subcommand rather than a payload file with the same name.
+/// The existing file wins, so that a payload file named e.g. `validate` can still be served.
+fn is_subcommand(args: &CliArgs, name: &str) -> bool {
+    args.positional.first().is_some_and(|v| v == name) && !std::path::Path::new(&resolve_payload_path(name)).exists()
+}
+
+/// Checks that the payload files can be read and contain valid JSON, one document per line for JSON Lines files,
+/// and validates them against LAMBDA_EMULATOR_REQUEST_SCHEMA, if set. Prints the outcome for every file.
+/// Returns true if all files are valid.
+fn validate_payload_files(payload_files: &[String], jsonl: bool) -> bool {
+    if payload_files.is_empty() {
+        println!("No payload files to validate, e.g. `cargo lambda-debugger validate event.json`");
+        return false;
+    }
+
+    let validator = load_schema("LAMBDA_EMULATOR_REQUEST_SCHEMA");
+
+    let mut is_valid = true;
+    for payload_file in payload_files {
+        match validate_payload_file(&resolve_payload_path(payload_file), jsonl, validator.as_ref()) {
+            Ok(1) => println!("OK    {payload_file}"),
+            Ok(count) => println!("OK    {payload_file}: {count} payloads"),
             Err(e) => {
-                panic!("Failed to read payload from {}\n{:?}", payload_file, e)
+                println!("ERROR {payload_file}: {e}");
+                is_valid = false;
             }
         }
+    }
+
+    is_valid
+}
+
+/// Validates a single payload file and returns the number of payloads in it or the first error.
+fn validate_payload_file(payload_file: &str, jsonl: bool, validator: Option<&Validator>) -> Result<usize, String> {
+    let contents = std::fs::read(payload_file).map_err(|e| e.to_string())?;
+    let contents = try_decode_payload(contents)?;
+
+    let payloads = if is_json_lines(payload_file, jsonl) {
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(idx, line)| (format!("line {}", idx + 1), line))
+            .collect::<Vec<(String, &str)>>()
     } else {
-        None
+        vec![("payload".to_owned(), contents.as_str())]
+    };
+
+    if payloads.is_empty() {
+        return Err("no payloads".to_owned());
+    }
+
+    for (label, payload) in &payloads {
+        if let Err(e) = serde_json::from_str::<serde::de::IgnoredAny>(payload) {
+            return Err(format!("invalid JSON in {label}: {e}"));
+        }
+        if validator.is_some_and(|v| !schema::validate(v, payload, label)) {
+            return Err(format!("{label} failed schema validation"));
+        }
+    }
+
+    Ok(payloads.len())
+}
+
+/// Wraps the contents of the payload file into LocalConfig. JSON Lines files are split into separate payloads.
+fn to_local_config(payload: String, payload_file: &str, jsonl: bool) -> LocalConfig {
+    let payloads = if is_json_lines(payload_file, jsonl) {
+        split_json_lines(&payload, payload_file)
+    } else {
+        vec![payload]
+    };
+
+    LocalConfig {
+        payloads,
+        command: None,
+        file_name: payload_file.to_owned(),
+    }
+}
+
+/// Splits the contents of a JSON Lines file into separate payloads, one per line.
+/// Blank lines are skipped. Panics if any of the lines is not a valid JSON.
+fn split_json_lines(contents: &str, file_name: &str) -> Vec<String> {
+    let payloads = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(_) => line.to_string(),
+            Err(e) => panic!("Invalid JSON in {file_name} on line {}\n{e}", idx + 1),
+        })
+        .collect::<Vec<String>>();
+
+    if payloads.is_empty() {
+        panic!("No payloads in {file_name}");
+    }
+
+    payloads
+}
+
+/// Prints the usage info in response to `--help` param.
+fn print_help() {
+    println!("AWS Lambda environment emulator for local and remote debugging.");
+    println!("1. run `cargo lambda-debugger`");
+    println!("2. copy the env vars printed by the emulator");
+    println!("3. set the env vars in a separate terminal and start your lambda there with `cargo run`");
+    println!();
+    println!("With local payload: cargo lambda-debugger [payload_file], e.g. lambda_payload.json");
+    println!("  push more events with POST /local/invoke, add ?wait=true to wait for the lambda response");
+    println!("With payload from AWS: cargo lambda-debugger");
+    println!("  check which SQS queues will be used with `cargo lambda-debugger list-queues`");
+    println!("  check the SQS permissions of your AWS profile with `cargo lambda-debugger --check-permissions`");
+    println!("  add --probe-receive to also check sqs:ReceiveMessage, which may receive a message from the queue");
+    println!("Check payload files without starting the emulator: cargo lambda-debugger validate [payload_file]...");
+    println!("  exits with 1 if any of the files is not valid JSON or fails LAMBDA_EMULATOR_REQUEST_SCHEMA");
+    println!();
+    println!("Options:");
+    println!("  --backend memory  use in-process queues instead of SQS: POST /local/enqueue, GET /local/dequeue");
+    println!("  --strict          reject events and responses that fail the schema validation");
+    println!("  --function-url    log the statusCode of function URL responses and use it for /local/invoke?wait=true");
+    println!("  --mutate path=gen set the field in the local payload to counter, uuid or timestamp on every invocation");
+    println!("  --pass-full-payload");
+    println!("                    send the whole SQS message with the event and the context to the lambda");
+    println!(
+        "  --cloudevents     wrap the events into CloudEvents v1.0 JSON envelopes before sending them to the lambda"
+    );
+    println!("  --env-file [file] set env vars from the file with NAME=value lines, re-read on SIGHUP");
+    println!(
+        "  --run [cmd]       start the lambda with the shell command and the env vars it needs, e.g. \"cargo run\""
+    );
+    println!("  --once            exit after the first invocation with code 0 on success or 2 on a lambda error");
+    println!("  --max-runtime [d] shut down after the duration regardless of activity, e.g. 60s, 5m or 1h");
+    println!("  --ready-banner    print `EMULATOR_READY {{json}}` line to stdout once the emulator is listening");
+    println!("  --expand-env      replace ${{VAR}} placeholders in the payload file with env var values");
+    println!("  --strict-env      stop with an error if --expand-env finds an undefined env var");
+    println!("  --jsonl           read one event per line from the payload file, implied by .jsonl extension");
+    println!("  --interval-ms [n] wait n milliseconds before sending each event from the payload file after the first");
+    println!("  --gen-cmd [cmd]   run the shell command when the lambda starts and send its stdout as the payload");
+    println!("  --replay-session [file]");
+    println!("                    send the events recorded in LAMBDA_EMULATOR_SESSION_FILE in the same order");
+    println!("  --gen ws          generate an API Gateway WebSocket event instead of reading it from a file");
+    println!("                    with --route [$default], --connection-id [id] and --body [message]");
+    println!("  --gen dynamodb-stream");
+    println!("                    generate a DynamoDB Streams event with --event-name [INSERT|MODIFY|REMOVE],");
+    println!("                    --key [name=value]... and --new-image [file] and --old-image [file]");
+    println!();
+    println!("See https://github.com/rimutaka/lambda-debugger-runtime-emulator for more info.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_env_vars_are_compacted() {
+        std::env::set_var("JSON_TEST_CLIENT_CONTEXT", "{\n  \"custom\": {\"a\": 1}\n}\n");
+
+        let value = get_json_env_var("JSON_TEST_CLIENT_CONTEXT").unwrap();
+
+        assert_eq!(value, r#"{"custom":{"a":1}}"#);
+        assert!(hyper::header::HeaderValue::from_str(&value).is_ok());
+    }
+
+    #[test]
+    fn json_lines_skip_blank_lines() {
+        let payloads = split_json_lines("{\"a\":1}\n\n   \n[2]\n", "test.jsonl");
+
+        assert_eq!(payloads, vec![r#"{"a":1}"#, "[2]"]);
+    }
+
+    #[test]
+    fn json_lines_strip_crlf() {
+        let payloads = split_json_lines("{\"a\":1}\r\n\r\n\"b\"\r\n", "test.jsonl");
+
+        assert_eq!(payloads, vec![r#"{"a":1}"#, r#""b""#]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid JSON in test.jsonl on line 3")]
+    fn json_lines_report_invalid_line_number() {
+        split_json_lines("{\"a\":1}\n\n{\"b\":\n", "test.jsonl");
+    }
+
+    #[test]
+    fn payload_paths_are_resolved_against_payload_dir() {
+        let absolute = std::env::temp_dir().join("event.json");
+        let absolute = absolute.to_str().unwrap();
+
+        std::env::remove_var("LAMBDA_EMULATOR_PAYLOAD_DIR");
+        assert_eq!(resolve_payload_path("events/event.json"), "events/event.json");
+
+        std::env::set_var("LAMBDA_EMULATOR_PAYLOAD_DIR", "/work/project ");
+        assert_eq!(
+            resolve_payload_path("events/event.json"),
+            std::path::Path::new("/work/project")
+                .join("events/event.json")
+                .to_str()
+                .unwrap()
+        );
+        assert_eq!(resolve_payload_path(absolute), absolute);
+        assert_eq!(resolve_payload_path("s3://bucket/event.json"), "s3://bucket/event.json");
+
+        std::env::remove_var("LAMBDA_EMULATOR_PAYLOAD_DIR");
+    }
+
+    #[test]
+    fn durations_are_parsed_with_units() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration(" 10 "), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        assert_eq!(parse_duration("1.5s"), None);
+        assert_eq!(parse_duration("-1s"), None);
+        assert_eq!(parse_duration("ms"), None);
+        assert_eq!(parse_duration("5d"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration(&format!("{}h", u64::MAX)), None);
+    }
+
+    #[test]
+    fn payload_files_are_validated() {
+        let valid = std::env::temp_dir().join(format!("lambda-debugger-{}-valid.jsonl", std::process::id()));
+        let broken = std::env::temp_dir().join(format!("lambda-debugger-{}-broken.jsonl", std::process::id()));
+        std::fs::write(&valid, "{\"a\":1}\n\n{\"a\":2}\n").unwrap();
+        std::fs::write(&broken, "{\"a\":1}\n{\"a\":\n").unwrap();
+        let valid = valid.to_string_lossy().into_owned();
+        let broken = broken.to_string_lossy().into_owned();
+
+        assert_eq!(validate_payload_file(&valid, false, None), Ok(2));
+        assert!(validate_payload_file(&broken, false, None).is_err_and(|e| e.contains("line 2")));
+        let payload_files = [valid, broken];
+        assert!(validate_payload_files(&payload_files[..1], false));
+        assert!(!validate_payload_files(&payload_files, false));
+
+        for payload_file in payload_files {
+            let _ = std::fs::remove_file(payload_file);
+        }
+    }
+
+    #[test]
+    fn env_file_lines_are_parsed() {
+        let contents = "# comment\n\nexport A=1\nB = \"two words\"\nC='x=y'\nD=\n";
+        let vars = parse_env_file(contents, "test.env");
+
+        assert_eq!(vars.len(), 4);
+        assert_eq!(vars["A"], "1");
+        assert_eq!(vars["B"], "two words");
+        assert_eq!(vars["C"], "x=y");
+        assert_eq!(vars["D"], "");
+    }
+
+    #[test]
+    fn env_vars_are_escaped_inside_strings() {
+        std::env::set_var("EXPAND_TEST_PATH", r#"C:\temp\"x""#);
+        std::env::set_var("EXPAND_TEST_COUNT", "42");
+
+        let payload =
+            r#"{"path":"${EXPAND_TEST_PATH}","note":"a \"${EXPAND_TEST_COUNT}\" b","count":${EXPAND_TEST_COUNT}}"#;
+        let expanded = expand_env_vars(payload, true);
+
+        let value = serde_json::from_str::<serde_json::Value>(&expanded).unwrap();
+        assert_eq!(value["path"], r#"C:\temp\"x""#);
+        assert_eq!(value["note"], r#"a "42" b"#);
+        assert_eq!(value["count"], 42);
+    }
+
+    #[test]
+    fn undefined_env_vars_are_left_as_is() {
+        let payload = r#"{"bucket":"${EXPAND_TEST_UNDEFINED}"}"#;
+        assert_eq!(expand_env_vars(payload, false), payload);
     }
 }
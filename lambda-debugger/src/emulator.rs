@@ -0,0 +1,553 @@
+use crate::config::Config;
+use crate::config::PayloadSources;
+use crate::handlers::{
+    reset_fault_rng, take_block_next_invocation, FIRST_INVOCATION, INVOCATIONS_RELEASED, NEXT_LOCAL_PAYLOAD,
+    UNBLOCK_INVOCATIONS,
+};
+use crate::{get_config, lambda_api_handler, HandlerError, CONFIG};
+use crate::{memory, runner, sqs};
+use arc_swap::ArcSwap;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::sleep;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+/// The reason the emulator has to stop, e.g. SQS being unreachable. It is taken by `Emulator::wait`.
+static FATAL_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Wakes up `Emulator::wait` when FATAL_ERROR is set.
+static FATAL_ERROR_SET: Notify = Notify::const_new();
+
+/// The outcome of the first invocation in `--once` mode. It is taken by `Emulator::wait`.
+static ONE_SHOT_OUTCOME: Mutex<Option<Result<(), HandlerError>>> = Mutex::new(None);
+
+/// Wakes up `Emulator::wait` when ONE_SHOT_OUTCOME is set.
+static ONE_SHOT_DONE: Notify = Notify::const_new();
+
+/// The loop answering requests with the canned response, if one is running.
+/// It is kept outside of `Emulator`, so that a canned response set on a config reload can start it.
+static CANNED_RESPONDER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Is set to TRUE while an emulator is running.
+/// The emulator state is global, so only one emulator can run in a process at a time.
+static IS_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// A running emulator listening for the lambda requests in the background.
+/// It stops when `shutdown` is called or when the handle is dropped.
+///
+/// The emulator state, e.g. the config and the queues, is process-global, so only one emulator can run
+/// in a process at a time. Tests that start emulators have to run one after another, e.g. behind a shared lock.
+pub struct Emulator {
+    /// The address the emulator is listening on
+    addr: SocketAddr,
+    /// Stops the listener loop when sent or dropped
+    shutdown: Option<oneshot::Sender<()>>,
+    /// The listener loop
+    listener: Option<JoinHandle<std::io::Result<()>>>,
+    /// The lambda started with `--run` param, if any
+    lambda: Option<JoinHandle<()>>,
+    /// Feeds the events sent via `sender` into the memory queue
+    events: mpsc::Sender<String>,
+    /// The loop forwarding the events from `events` channel
+    event_forwarder: Option<JoinHandle<()>>,
+}
+
+impl Emulator {
+    /// Binds to the listener address from the config and starts serving the lambda requests in the background.
+    /// Returns an error if another emulator is already running in this process.
+    pub async fn start(config: Config) -> std::io::Result<Self> {
+        if IS_RUNNING.swap(true, Ordering::SeqCst) {
+            return Err(std::io::Error::other(
+                "Another emulator is already running in this process. Shut it down first.",
+            ));
+        }
+
+        // bind to a TCP port and start a loop to continuously accept incoming connections
+        let listener = match TcpListener::bind(config.lambda_api_listener).await {
+            Ok(v) => v,
+            Err(e) => {
+                IS_RUNNING.store(false, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        let addr = listener.local_addr()?;
+
+        // a single line for the tools that launch the emulator to know it is ready
+        if config.ready_banner {
+            println!(
+                "EMULATOR_READY {}",
+                serde_json::json!({ "addr": addr.to_string(), "mode": config.mode() })
+            );
+        }
+
+        // the config and the invocation state may be left over from the previous emulator
+        match CONFIG.get() {
+            Some(current) => current.store(Arc::new(config)),
+            None => {
+                if CONFIG.set(ArcSwap::from_pointee(config)).is_err() {
+                    panic!("Config is already initialized. It's a bug.");
+                }
+            }
+        }
+        take_block_next_invocation();
+        INVOCATIONS_RELEASED.store(false, Ordering::SeqCst);
+        if let Ok(mut fatal_error) = FATAL_ERROR.lock() {
+            *fatal_error = None;
+        }
+        if let Ok(mut outcome) = ONE_SHOT_OUTCOME.lock() {
+            *outcome = None;
+        }
+        NEXT_LOCAL_PAYLOAD.store(0, Ordering::Relaxed);
+        FIRST_INVOCATION.store(true, Ordering::Relaxed);
+        reset_fault_rng();
+        sqs::clear_response_buffer();
+
+        // the TLS settings cannot be changed without a restart, same as the listener address
+        let tls = get_config().tls.clone().map(TlsAcceptor::from);
+
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let listener = tokio::spawn(accept_connections(listener, tls, shutdown_receiver));
+
+        start_canned_responder();
+
+        let lambda = get_config().run.as_deref().map(|v| runner::spawn(v, addr));
+
+        let (events, receiver) = mpsc::channel(1);
+        let event_forwarder = Some(tokio::spawn(forward_events(receiver)));
+
+        Ok(Self {
+            addr,
+            shutdown: Some(shutdown),
+            listener: Some(listener),
+            lambda,
+            events,
+            event_forwarder,
+        })
+    }
+
+    /// Returns the address the emulator is listening on, e.g. for AWS_LAMBDA_RUNTIME_API env var of the lambda.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Adds the event to the in-memory queue and returns the request ID assigned to it,
+    /// or None if the queue is full. The queued events are served ahead of the payload file
+    /// in local mode, same as the events pushed via `POST /local/invoke`, and in memory backend mode.
+    /// They are not served in remote mode.
+    pub fn enqueue(&self, payload: impl Into<String>) -> Option<String> {
+        memory::enqueue(payload.into(), false).map(|(request_id, _)| request_id)
+    }
+
+    /// Returns a channel for pushing events into the in-memory queue from other tasks or threads,
+    /// e.g. with `blocking_send` from a non-async test. Same as `enqueue`, but the events wait
+    /// for a free slot instead of being rejected when the queue is full.
+    pub fn sender(&self) -> mpsc::Sender<String> {
+        self.events.clone()
+    }
+
+    /// Waits for the emulator to stop, which only happens on a listener error
+    /// or when the emulator is shut down after `--max-runtime` or on a fatal error, e.g. SQS being unreachable.
+    /// In `--once` mode it stops after the first invocation and returns the lambda error, if any,
+    /// as `HandlerError` wrapped into `std::io::Error`.
+    pub async fn wait(mut self) -> std::io::Result<()> {
+        let Some(mut listener) = self.listener.take() else {
+            return Ok(());
+        };
+
+        let max_runtime = get_config().max_runtime;
+        let has_lambda = self.lambda.is_some();
+        let timer = async {
+            match max_runtime {
+                Some(v) => sleep(v).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            result = &mut listener => {
+                IS_RUNNING.store(false, Ordering::SeqCst);
+                result.unwrap_or_else(|e| Err(std::io::Error::other(e)))
+            }
+            _ = timer => {
+                info!("Max runtime of {}s reached. Shutting down.", max_runtime.unwrap_or_default().as_secs());
+                self.listener = Some(listener);
+                self.shutdown().await
+            }
+            message = fatal_error() => {
+                error!("{message}");
+                self.listener = Some(listener);
+                self.shutdown().await?;
+                Err(std::io::Error::other(message))
+            }
+            // the lambda started with --run is in its own process group, so it has to be stopped by the emulator
+            _ = stop_signal(), if has_lambda => {
+                info!("Stopping the lambda and shutting down");
+                self.listener = Some(listener);
+                self.shutdown().await
+            }
+            outcome = one_shot_outcome() => {
+                info!("The invocation is complete. Shutting down as per --once.");
+                self.listener = Some(listener);
+                self.shutdown().await?;
+                outcome.map_err(std::io::Error::other)
+            }
+        }
+    }
+
+    /// Stops accepting new connections, drops the existing ones and waits for the emulator to stop.
+    pub async fn shutdown(mut self) -> std::io::Result<()> {
+        INVOCATIONS_RELEASED.store(true, Ordering::SeqCst);
+        UNBLOCK_INVOCATIONS.notify_waiters();
+
+        if let Some(event_forwarder) = self.event_forwarder.take() {
+            event_forwarder.abort();
+        }
+
+        stop_canned_responder();
+
+        // the lambda process is killed when its task is dropped, which happens after the task is aborted
+        if let Some(lambda) = self.lambda.take() {
+            lambda.abort();
+            let _ = lambda.await;
+        }
+
+        // the requests of the responses still waiting to be sent stay in the queue
+        sqs::clear_response_buffer();
+
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+
+        self.join().await
+    }
+
+    /// Re-reads the config every time the emulator receives SIGHUP and replaces the current config with the new one.
+    /// The env vars inherited from the shell cannot change, so only the payload files and the env file
+    /// from `--env-file` param are re-read.
+    /// The listener address and the capacity of the in-memory queue cannot be changed without a restart.
+    #[cfg(unix)]
+    pub fn reload_config_on_sighup(&self) {
+        reload_config_on_sighup();
+    }
+
+    /// Waits for the listener loop to finish.
+    async fn join(&mut self) -> std::io::Result<()> {
+        let result = match self.listener.take() {
+            Some(listener) => match listener.await {
+                Ok(v) => v,
+                Err(e) => Err(std::io::Error::other(e)),
+            },
+            None => Ok(()),
+        };
+
+        IS_RUNNING.store(false, Ordering::SeqCst);
+
+        result
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        if let Some(event_forwarder) = self.event_forwarder.take() {
+            event_forwarder.abort();
+        }
+
+        stop_canned_responder();
+
+        // the lambda process is killed when its task is dropped
+        if let Some(lambda) = self.lambda.take() {
+            lambda.abort();
+        }
+
+        sqs::clear_response_buffer();
+
+        // the listener loop stops when the shutdown sender is dropped
+        if self.listener.is_some() {
+            IS_RUNNING.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Makes `Emulator::wait` shut down the emulator and return the error, so that the process exits with non-zero code.
+/// Only the first error is kept.
+pub(crate) fn stop_with_error(message: String) {
+    if let Ok(mut fatal_error) = FATAL_ERROR.lock() {
+        fatal_error.get_or_insert(message);
+    }
+    FATAL_ERROR_SET.notify_one();
+}
+
+/// Waits for a fatal error and returns its message.
+/// Wake-ups left over from a previous emulator are ignored because the error is reset on start.
+async fn fatal_error() -> String {
+    loop {
+        FATAL_ERROR_SET.notified().await;
+        if let Some(message) = FATAL_ERROR.lock().ok().and_then(|mut v| v.take()) {
+            return message;
+        }
+    }
+}
+
+/// Waits for Ctrl+C or SIGTERM. Never returns if the signals cannot be listened to.
+async fn stop_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut v) => {
+                v.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if result.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+        _ = terminate => {}
+    }
+}
+
+/// Moves the events sent via `Emulator::sender` into the memory queue until the emulator stops.
+async fn forward_events(mut receiver: mpsc::Receiver<String>) {
+    while let Some(payload) = receiver.recv().await {
+        memory::enqueue_when_ready(payload).await;
+    }
+}
+
+/// Makes `Emulator::wait` shut down the emulator in `--once` mode and return the outcome of the invocation.
+/// Only the first outcome is kept.
+pub(crate) fn finish_one_shot(outcome: Result<(), HandlerError>) {
+    if let Ok(mut one_shot_outcome) = ONE_SHOT_OUTCOME.lock() {
+        one_shot_outcome.get_or_insert(outcome);
+    }
+    ONE_SHOT_DONE.notify_one();
+}
+
+/// Waits for the outcome of the invocation in `--once` mode.
+async fn one_shot_outcome() -> Result<(), HandlerError> {
+    loop {
+        ONE_SHOT_DONE.notified().await;
+        if let Some(outcome) = ONE_SHOT_OUTCOME.lock().ok().and_then(|mut v| v.take()) {
+            return outcome;
+        }
+    }
+}
+
+/// Accepts connections from the lambda until the shutdown signal is received.
+/// The connections are wrapped into TLS if the acceptor is set.
+/// The open connections are dropped on shutdown.
+async fn accept_connections(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    mut shutdown: oneshot::Receiver<()>,
+) -> std::io::Result<()> {
+    let mut connections = JoinSet::new();
+
+    loop {
+        let stream = tokio::select! {
+            v = listener.accept() => v?.0,
+            _ = &mut shutdown => {
+                info!("Emulator stopped");
+                return Ok(());
+            }
+            // clean up the finished connections
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+        };
+
+        // Spawn a tokio task to serve multiple connections concurrently
+        let tls = tls.clone();
+        connections.spawn(async move {
+            match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => serve_connection(stream).await,
+                    Err(e) => warn!("TLS handshake failed: {:?}", e),
+                },
+                None => serve_connection(stream).await,
+            }
+        });
+    }
+}
+
+/// Serves the lambda requests from a single connection until it is closed.
+async fn serve_connection<S>(stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // bind the incoming connection to lambda_api_handler service
+    if let Err(err) = http1::Builder::new()
+        // `service_fn` comes from Tower, convert the handler function into a service
+        .serve_connection(TokioIo::new(stream), service_fn(lambda_api_handler))
+        .await
+    {
+        debug!("TCP error: {:?}", err);
+        info!("Lambda disconnected\n")
+    }
+}
+
+/// Answers every request from the request queue with the canned response instead of the lambda,
+/// as if the lambda responded instantly. It stops if the canned response is removed from the config.
+async fn send_canned_responses() {
+    loop {
+        let config = get_config();
+        let (Some(response), Some(source), Some(sink)) =
+            (&config.canned_response, config.message_source(), config.message_sink())
+        else {
+            info!("Stopped sending canned responses");
+            return;
+        };
+
+        let message = match source.next().await {
+            Ok(v) => v,
+            Err(e) => {
+                stop_with_error(e);
+                return;
+            }
+        };
+        info!("Lambda request:\n{}", message.payload);
+        info!("Sending the canned response");
+
+        sink.send(response.clone(), message.receipt_handle).await;
+    }
+}
+
+/// Starts answering the requests with the canned response from the config, if there is one
+/// and the canned responder is not running yet.
+fn start_canned_responder() {
+    if get_config().canned_response.is_none() {
+        return;
+    }
+
+    if let Ok(mut canned_responder) = CANNED_RESPONDER.lock() {
+        if canned_responder.as_ref().is_some_and(|v| !v.is_finished()) {
+            return;
+        }
+        *canned_responder = Some(tokio::spawn(send_canned_responses()));
+    }
+}
+
+/// Stops answering the requests with the canned response.
+fn stop_canned_responder() {
+    if let Some(task) = CANNED_RESPONDER.lock().ok().and_then(|mut v| v.take()) {
+        task.abort();
+    }
+}
+
+/// Spawns a task that reloads the config on SIGHUP.
+#[cfg(unix)]
+fn reload_config_on_sighup() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to listen for SIGHUP. Config reloading is disabled.\n{:?}", e);
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received: reloading the config");
+
+            // Config::from_env panics on invalid values, so it runs in a separate task
+            // to keep the current config if the new one cannot be loaded
+            let config = match tokio::spawn(Config::from_env()).await {
+                Ok(v) => v,
+                Err(_) => {
+                    error!("Failed to reload the config. The current config is still in use.");
+                    continue;
+                }
+            };
+
+            let current = get_config();
+            if config.lambda_api_listener != current.lambda_api_listener {
+                warn!(
+                    "The emulator keeps listening on http://{}. Restart it to listen on http://{}",
+                    current.lambda_api_listener, config.lambda_api_listener
+                );
+            }
+            if let (PayloadSources::Memory(current), PayloadSources::Memory(new)) = (&current.sources, &config.sources)
+            {
+                if current.capacity != new.capacity {
+                    warn!(
+                        "The in-memory queue keeps its capacity of {}. Restart the emulator to change it to {}",
+                        current.capacity, new.capacity
+                    );
+                }
+            }
+
+            CONFIG
+                .get()
+                .expect("Config is reloaded before it was initialized. It's a bug.")
+                .store(Arc::new(config));
+
+            // the payloads and the injected faults start over with the new config
+            // the events already in the in-memory queue are kept
+            NEXT_LOCAL_PAYLOAD.store(0, Ordering::Relaxed);
+            reset_fault_rng();
+
+            // the canned responder stops on its own when the canned response is removed from the config
+            start_canned_responder();
+
+            info!("Config reloaded");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// Sends a GET request to the emulator and returns the raw HTTP response.
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn enqueued_event_is_served_until_shutdown() {
+        let payload_file = std::env::temp_dir().join("lambda-debugger-emulator-test.json");
+        std::fs::write(&payload_file, r#"{"from":"file"}"#).unwrap();
+
+        let emu = Emulator::start(Config::local(payload_file.to_str().unwrap()))
+            .await
+            .unwrap();
+
+        // the state is global, so a second emulator is rejected while the first one runs
+        let second = Emulator::start(Config::local(payload_file.to_str().unwrap())).await;
+        assert!(second.is_err());
+
+        let request_id = emu.enqueue(r#"{"from":"queue"}"#).unwrap();
+        let response = get(emu.addr(), "/2018-06-01/runtime/invocation/next").await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains(&format!("lambda-runtime-aws-request-id: {request_id}")));
+        assert!(response.ends_with(r#"{"from":"queue"}"#), "{response}");
+
+        emu.shutdown().await.unwrap();
+        assert!(!IS_RUNNING.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,290 @@
+use crate::args::CliArgs;
+use serde_json::{json, Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Kinds of synthetic events the emulator can generate instead of reading the payload from a file.
+/// Selected with `--gen [kind]` param.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Generator {
+    /// API Gateway WebSocket API event, `--gen ws`
+    WebSocket,
+    /// DynamoDB Streams event with a single record, `--gen dynamodb-stream`
+    DynamoDbStream,
+}
+
+impl Generator {
+    /// Returns the generator selected with `--gen` param, if any.
+    /// Panics if the kind is not supported.
+    pub fn from_args(args: &CliArgs) -> Option<Self> {
+        match args.value("--gen")? {
+            "ws" => Some(Self::WebSocket),
+            "dynamodb-stream" => Some(Self::DynamoDbStream),
+            v => panic!("Invalid --gen value: {v}. Must be `ws` or `dynamodb-stream`."),
+        }
+    }
+
+    /// Returns a synthetic event built from the command line params.
+    pub fn event(&self, args: &CliArgs) -> String {
+        match self {
+            Self::WebSocket => ws_event(
+                args.value("--route").unwrap_or("$default"),
+                args.value("--connection-id").unwrap_or("local-connection-id"),
+                args.value("--body"),
+            ),
+            Self::DynamoDbStream => dynamodb_stream_event(
+                args.value("--event-name").unwrap_or("INSERT"),
+                &args.values("--key"),
+                args.value("--new-image").map(read_image),
+                args.value("--old-image").map(read_image),
+            ),
+        }
+    }
+
+    /// Logs the parts of the lambda response that matter for this kind of event.
+    pub fn inspect_response(&self, response: &str) {
+        match self {
+            // API Gateway uses the status code from the response to tell if the route succeeded
+            Self::WebSocket => match serde_json::from_str::<Value>(response)
+                .ok()
+                .and_then(|v| v.get("statusCode").and_then(Value::as_u64))
+            {
+                Some(status_code) => info!("WebSocket route response status code: {status_code}"),
+                None => warn!("WebSocket route response has no statusCode. API Gateway treats it as 200."),
+            },
+            // a partial batch response lists the records to be retried
+            Self::DynamoDbStream => match serde_json::from_str::<Value>(response)
+                .ok()
+                .and_then(|v| v.get("batchItemFailures").and_then(Value::as_array).cloned())
+            {
+                Some(failures) if !failures.is_empty() => {
+                    let failures = Value::Array(failures).to_string();
+                    warn!("DynamoDB stream batch item failures: {failures}")
+                }
+                _ => info!("DynamoDB stream record processed"),
+            },
+        }
+    }
+}
+
+/// Builds an API Gateway WebSocket API event for the route.
+/// `$connect` and `$disconnect` routes have no body. All other routes are treated as messages.
+fn ws_event(route: &str, connection_id: &str, body: Option<&str>) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the epoch. It's a bug.")
+        .as_millis() as u64;
+
+    let event_type = match route {
+        "$connect" => "CONNECT",
+        "$disconnect" => "DISCONNECT",
+        _ => "MESSAGE",
+    };
+
+    let mut event = json!({
+        "requestContext": {
+            "routeKey": route,
+            "eventType": event_type,
+            "messageDirection": "IN",
+            "stage": "local",
+            "connectedAt": now,
+            "requestTimeEpoch": now,
+            "requestId": uuid::Uuid::new_v4().to_string(),
+            "domainName": "localhost",
+            "connectionId": connection_id,
+            "apiId": "local",
+        },
+        "isBase64Encoded": false,
+    });
+
+    if event_type == "MESSAGE" {
+        event["requestContext"]["messageId"] = json!(uuid::Uuid::new_v4().to_string());
+        event["body"] = json!(body.unwrap_or("{}"));
+    }
+
+    event.to_string()
+}
+
+/// Builds a DynamoDB Streams event with a single record for the item with the keys and images.
+/// INSERT records have only the new image, REMOVE records have only the old one and MODIFY records have both.
+/// The keys are added to the images because DynamoDB images always contain the key attributes.
+/// Panics on invalid event names and keys because there is no point sending a malformed event.
+fn dynamodb_stream_event(
+    event_name: &str,
+    keys: &[&str],
+    new_image: Option<Value>,
+    old_image: Option<Value>,
+) -> String {
+    if !matches!(event_name, "INSERT" | "MODIFY" | "REMOVE") {
+        panic!("Invalid --event-name value: {event_name}. Must be INSERT, MODIFY or REMOVE.");
+    }
+
+    if keys.is_empty() {
+        panic!("DynamoDB stream events need at least one key, e.g. `--key id=123`");
+    }
+
+    // numbers become N attributes, everything else is a string
+    let keys = keys
+        .iter()
+        .map(|key| match key.split_once('=') {
+            Some((name, value)) if is_decimal(value) => (name.to_owned(), json!({ "N": value })),
+            Some((name, value)) => (name.to_owned(), json!({ "S": value })),
+            None => panic!("Invalid --key value: {key}. Must be name=value, e.g. id=123"),
+        })
+        .collect::<Map<String, Value>>();
+
+    let with_keys = |image: Option<Value>| {
+        let mut image = match image.map(to_attribute_value) {
+            Some(Value::Object(mut v)) => match v.remove("M") {
+                Some(Value::Object(v)) => v,
+                _ => Map::new(),
+            },
+            _ => Map::new(),
+        };
+        image.extend(keys.clone());
+        Value::Object(image)
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the epoch. It's a bug.");
+
+    let mut dynamodb = json!({
+        "ApproximateCreationDateTime": now.as_secs(),
+        "Keys": keys,
+        "SequenceNumber": now.as_nanos().to_string(),
+        "StreamViewType": "NEW_AND_OLD_IMAGES",
+    });
+    if event_name != "REMOVE" {
+        dynamodb["NewImage"] = with_keys(new_image);
+    }
+    if event_name != "INSERT" {
+        dynamodb["OldImage"] = with_keys(old_image);
+    }
+    dynamodb["SizeBytes"] = json!(dynamodb.to_string().len());
+
+    json!({
+        "Records": [{
+            "eventID": uuid::Uuid::new_v4().simple().to_string(),
+            "eventName": event_name,
+            "eventVersion": "1.1",
+            "eventSource": "aws:dynamodb",
+            "awsRegion": "us-east-1",
+            "dynamodb": dynamodb,
+            "eventSourceARN": "arn:aws:dynamodb:us-east-1:123456789012:table/local/stream/2024-01-01T00:00:00.000",
+        }],
+    })
+    .to_string()
+}
+
+/// Returns true if the key value is a plain decimal number, e.g. `123` or `-1.5`.
+/// Values like `NaN`, `inf` or `1e999` are parsed by Rust as numbers, but DynamoDB rejects them as N attributes.
+fn is_decimal(value: &str) -> bool {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    let (int, fraction) = digits.split_once('.').unwrap_or((digits, "0"));
+    !int.is_empty() && !fraction.is_empty() && int.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit())
+}
+
+/// Reads a sample item for `--new-image` or `--old-image` param from a JSON file with a plain JSON object,
+/// e.g. `{"id": "123", "count": 5}`. Panics if the file cannot be read or is not a JSON object.
+fn read_image(file_name: &str) -> Value {
+    let contents = match std::fs::read_to_string(file_name) {
+        Ok(v) => v,
+        Err(e) => panic!("Failed to read DynamoDB image from {file_name}\n{:?}", e),
+    };
+
+    match serde_json::from_str::<Value>(&contents) {
+        Ok(v) if v.is_object() => v,
+        Ok(_) => panic!("DynamoDB image in {file_name} must be a JSON object"),
+        Err(e) => panic!("Invalid JSON in DynamoDB image {file_name}\n{e}"),
+    }
+}
+
+/// Converts a plain JSON value into a DynamoDB attribute value, e.g. `5` into `{"N": "5"}`.
+fn to_attribute_value(value: Value) -> Value {
+    match value {
+        Value::Null => json!({ "NULL": true }),
+        Value::Bool(v) => json!({ "BOOL": v }),
+        Value::Number(v) => json!({ "N": v.to_string() }),
+        Value::String(v) => json!({ "S": v }),
+        Value::Array(v) => json!({ "L": v.into_iter().map(to_attribute_value).collect::<Vec<Value>>() }),
+        Value::Object(v) => json!({
+            "M": v.into_iter().map(|(k, v)| (k, to_attribute_value(v))).collect::<Map<String, Value>>()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(event_name: &str, keys: &[&str]) -> Value {
+        let event = dynamodb_stream_event(
+            event_name,
+            keys,
+            Some(json!({ "name": "new" })),
+            Some(json!({ "name": "old" })),
+        );
+        serde_json::from_str::<Value>(&event).unwrap()["Records"][0].clone()
+    }
+
+    #[test]
+    fn images_match_the_event_name() {
+        let insert = record("INSERT", &["id=1"]);
+        assert_eq!(insert["eventName"], "INSERT");
+        assert_eq!(
+            insert["dynamodb"]["NewImage"],
+            json!({ "id": { "N": "1" }, "name": { "S": "new" } })
+        );
+        assert!(insert["dynamodb"].get("OldImage").is_none());
+
+        let modify = record("MODIFY", &["id=1"]);
+        assert_eq!(modify["dynamodb"]["NewImage"]["name"], json!({ "S": "new" }));
+        assert_eq!(modify["dynamodb"]["OldImage"]["name"], json!({ "S": "old" }));
+
+        let remove = record("REMOVE", &["id=1"]);
+        assert!(remove["dynamodb"].get("NewImage").is_none());
+        assert_eq!(
+            remove["dynamodb"]["OldImage"],
+            json!({ "id": { "N": "1" }, "name": { "S": "old" } })
+        );
+    }
+
+    #[test]
+    fn only_decimal_keys_are_numbers() {
+        let keys = record(
+            "INSERT",
+            &["a=123", "b=-1.5", "c=NaN", "d=inf", "e=1e999", "f=1.", "g=abc"],
+        );
+        assert_eq!(
+            keys["dynamodb"]["Keys"],
+            json!({
+                "a": { "N": "123" },
+                "b": { "N": "-1.5" },
+                "c": { "S": "NaN" },
+                "d": { "S": "inf" },
+                "e": { "S": "1e999" },
+                "f": { "S": "1." },
+                "g": { "S": "abc" },
+            })
+        );
+    }
+
+    #[test]
+    fn json_values_are_converted_to_attribute_values() {
+        let value = json!({ "n": null, "b": true, "i": 5, "f": 1.5, "s": "x", "l": [1, "y"], "m": { "k": false } });
+
+        assert_eq!(
+            to_attribute_value(value),
+            json!({ "M": {
+                "n": { "NULL": true },
+                "b": { "BOOL": true },
+                "i": { "N": "5" },
+                "f": { "N": "1.5" },
+                "s": { "S": "x" },
+                "l": { "L": [{ "N": "1" }, { "S": "y" }] },
+                "m": { "M": { "k": { "BOOL": false } } },
+            }})
+        );
+    }
+}
@@ -0,0 +1,24 @@
+use super::full;
+use crate::sqs;
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::{Error, Request, Response};
+
+/// Handles `GET /runtime/dropped` requests by returning the number of responses dropped because they were too big
+/// for SQS and the sizes of the last few, e.g. `{"count":2,"last_sizes":[300000,280000]}`.
+/// Add `?reset=true` to reset the stats after reading them.
+/// This is not a part of the Lambda Runtime API. It is meant for scripts and UIs wrapping the emulator.
+pub(crate) async fn handler(req: Request<hyper::body::Incoming>) -> Response<BoxBody<Bytes, Error>> {
+    let reset = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .any(|v| v == "reset=true" || v == "reset=1");
+
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(full(sqs::dropped_responses(reset)))
+        .expect("Failed to create a response")
+}
@@ -1,23 +1,94 @@
-use super::{empty, BLOCK_NEXT_INVOCATION};
+use super::{block_next_invocation, empty, full, LAST_ERROR, LOCAL_REQUEST_ID};
+use crate::config::PayloadSources;
+use crate::emulator;
+use crate::get_config;
+use crate::invocations;
+use crate::memory;
+use crate::session::{self, Direction};
+use crate::sqs;
+use crate::HandlerError;
 use http_body_util::{combinators::BoxBody, BodyExt};
 use hyper::body::Bytes;
 use hyper::Error;
 use hyper::{Request, Response};
-use tracing::{debug, error, info};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::OnceLock;
+use tracing::{debug, error, info, warn};
+
+/// Contains compiled regex for extracting the receipt handle from the URL.
+static RECEIPT_REGEX: OnceLock<Regex> = OnceLock::new();
+
+/// The error body as defined by the runtime API spec, e.g.
+/// `{"errorMessage": "...", "errorType": "...", "stackTrace": ["..."]}`.
+/// All fields are optional because runtimes differ in what they send.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LambdaError {
+    error_type: Option<String>,
+    error_message: Option<String>,
+    #[serde(default)]
+    stack_trace: Vec<Value>,
+}
+
+impl LambdaError {
+    /// Parses the error body. Returns None if it does not look like a structured error.
+    fn parse(body: &str) -> Option<Self> {
+        serde_json::from_str::<Self>(body)
+            .ok()
+            .filter(|v| v.error_type.is_some() || v.error_message.is_some())
+    }
+
+    /// Formats the error type, the message and the first stack frame on separate lines for the log.
+    fn summary(&self) -> String {
+        let mut summary = format!(
+            "Lambda error\n  type: {}\n  message: {}",
+            self.error_type.as_deref().unwrap_or_default(),
+            self.error_message.as_deref().unwrap_or_default()
+        );
+
+        // frames are usually strings, but some runtimes send objects
+        match self.stack_trace.first() {
+            Some(Value::String(frame)) => summary.push_str(&format!("\n  at: {frame}")),
+            Some(frame) => summary.push_str(&format!("\n  at: {frame}")),
+            None => {}
+        }
+
+        summary
+    }
+}
 
 pub(crate) async fn handler(req: Request<hyper::body::Incoming>) -> Response<BoxBody<Bytes, Error>> {
     // Initialization error (https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html#runtimes-api-initerror) and
     // Invocation error (https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html#runtimes-api-invokeerror)
     // are rolled together into a single handler because it is not clear how to handle errors
     // and if the error should be propagated upstream
+
+    // invocation errors have the receipt handle in the URL, e.g. /runtime/invocation/[aws-req-id]/error,
+    // but initialization errors do not
+    let regex = RECEIPT_REGEX
+        .get_or_init(|| Regex::new(r"/runtime/invocation/(.+)/error").expect("Invalid error URL regex. It's a bug."));
+    let receipt_handle = regex
+        .captures(req.uri().path())
+        .and_then(|c| c.get(1))
+        .map(|v| v.as_str().to_owned());
+
     let resp = match req.into_body().collect().await {
         Ok(v) => v.to_bytes(),
         Err(e) => panic!("Failed to read lambda response: {:?}", e),
     };
 
-    match String::from_utf8(resp.as_ref().to_vec()) {
+    let lambda_error = match String::from_utf8(resp.as_ref().to_vec()) {
         Ok(v) => {
-            info!("Lambda error: {v}");
+            match LambdaError::parse(&v) {
+                Some(lambda_error) => {
+                    info!("{}", lambda_error.summary());
+                    debug!("Lambda error: {v}");
+                }
+                None => info!("Lambda error: {v}"),
+            }
+            Some(v)
         }
         Err(e) => {
             error!(
@@ -25,20 +96,111 @@ pub(crate) async fn handler(req: Request<hyper::body::Incoming>) -> Response<Box
                 e,
                 hex::encode(resp.as_ref())
             );
+            None
         }
+    };
+
+    // keep the error for inspection via GET /runtime/last-error
+    if let Some(lambda_error) = &lambda_error {
+        if let Ok(mut w) = LAST_ERROR.write() {
+            *w = Some(lambda_error.clone());
+        } else {
+            error!("Write deadlock on LAST_ERROR. It's a bug");
+        }
+    }
+
+    // AWS discards the errors that arrive after the timeout
+    if receipt_handle.as_ref().is_some_and(|v| invocations::take_timed_out(v)) {
+        warn!("The error arrived after the invocation timed out and was discarded");
+        return Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(empty())
+            .expect("Failed to create a response");
     }
 
-    // block the next invocation to prevent an infinite loop of reruns
-    if let Ok(mut w) = BLOCK_NEXT_INVOCATION.write() {
-        debug!("Blocking the next invocation");
-        *w = true;
+    // initialization errors have no request ID
+    if let Some(lambda_error) = &lambda_error {
+        session::record(
+            Direction::Error,
+            receipt_handle.as_deref().unwrap_or_default(),
+            lambda_error,
+        );
+    }
+
+    let invocation = receipt_handle.as_ref().and_then(|v| invocations::finish(v));
+    if let Some(message_id) = invocation.as_ref().and_then(|v| v.message_id.as_deref()) {
+        info!("Error for SQS message ID: {message_id}");
+    }
+
+    let config = get_config();
+
+    // rerun the failed invocation straight away up to LAMBDA_EMULATOR_MAX_RERUNS times
+    // events from the memory queue are not rerun because their outcome goes back to the caller
+    let rerun = match &receipt_handle {
+        Some(receipt_handle) if config.max_reruns > 0 && !memory::is_in_flight(receipt_handle) => {
+            invocations::try_rerun(
+                &invocations::rerun_key(receipt_handle, invocation.as_ref()),
+                config.max_reruns,
+            )
+        }
+        _ => None,
+    };
+
+    if let (Some(rerun), Some(receipt_handle)) = (rerun, &receipt_handle) {
+        info!("Rerunning the failed invocation: {rerun} of {}", config.max_reruns);
+        if matches!(config.sources, PayloadSources::Remote(_)) && receipt_handle != LOCAL_REQUEST_ID {
+            sqs::release_message(receipt_handle).await;
+        }
+    } else if !config.block_on_error {
+        debug!("Not blocking the next invocation as per LAMBDA_EMULATOR_NO_BLOCK_ON_ERROR");
     } else {
-        error!("Write deadlock on BLOCK_NEXT_INVOCATION. It's a bug");
+        // block the next invocation to prevent an infinite loop of reruns
+        block_next_invocation();
+    }
+
+    // the invocation is over unless it is rerun
+    if let (true, None, Some(body)) = (config.once, rerun, &lambda_error) {
+        let (error_type, error_message) = match LambdaError::parse(body) {
+            Some(v) => (v.error_type, v.error_message),
+            None => (None, None),
+        };
+        emulator::finish_one_shot(Err(HandlerError {
+            error_type,
+            error_message,
+            body: body.clone(),
+        }));
+    }
+
+    // return the error to the local caller waiting for it via /local/invoke?wait=true
+    if let (Some(lambda_error), Some(receipt_handle), PayloadSources::Local(_) | PayloadSources::Memory(_)) =
+        (&lambda_error, &receipt_handle, &config.sources)
+    {
+        if memory::is_in_flight(receipt_handle) {
+            memory::send_error(lambda_error.clone(), receipt_handle);
+        }
+    }
+
+    if !config.echo_errors {
+        // lambda allows for more informative error responses, but this may be enough for now
+        return Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(empty())
+            .expect("Failed to create a response");
+    }
+
+    // forward invocation errors to the caller if the request came from SQS
+    // the caller keeps waiting if the invocation is rerun
+    if let (Some(lambda_error), Some(receipt_handle), PayloadSources::Remote(_)) =
+        (&lambda_error, &receipt_handle, &config.sources)
+    {
+        if receipt_handle != LOCAL_REQUEST_ID && rerun.is_none() {
+            sqs::send_error(lambda_error.clone()).await;
+        }
     }
 
-    // lambda allows for more informative error responses, but this may be enough for now
+    // echo the error back to the lambda
     Response::builder()
         .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-        .body(empty())
+        .body(full(resp))
         .expect("Failed to create a response")
 }
@@ -1,4 +1,11 @@
-use super::{empty, BLOCK_NEXT_INVOCATION, LOCAL_REQUEST_ID};
+use super::{block_next_invocation, empty, full, inject_fault, LOCAL_REQUEST_ID, NEXT_LOCAL_PAYLOAD};
+use crate::config::{Config, PayloadSources};
+use crate::get_config;
+use crate::invocations;
+use crate::memory::{self, MemoryBackend};
+use crate::queues::MessageSink;
+use crate::schema;
+use crate::session::{self, Direction};
 use crate::sqs;
 use http_body_util::{combinators::BoxBody, BodyExt};
 use hyper::body::Bytes;
@@ -6,8 +13,10 @@ use hyper::Error;
 use hyper::Request;
 use hyper::Response;
 use regex::Regex;
+use serde_json::Value;
+use std::sync::atomic::Ordering;
 use std::sync::OnceLock;
-use tracing::{debug, error, info};
+use tracing::{debug, info, warn};
 
 /// Contains compiled regex for extracting the receipt handle from the URL.
 static RECEIPT_REGEX: OnceLock<Regex> = OnceLock::new();
@@ -42,10 +51,25 @@ pub(crate) async fn handler(req: Request<hyper::body::Incoming>) -> Response<Box
         .as_str()
         .to_owned();
 
+    // the lambda can ask to keep the request message in the queue, e.g. to test its idempotency
+    let keep_request = req
+        .headers()
+        .get("x-emulator-keep-request")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("true") || v == "1");
+
     // convert the lambda response to bytes
+    // hyper fails the body if it is shorter than its Content-Length, e.g. if the lambda crashed while sending it
     let response = match req.into_body().collect().await {
         Ok(v) => v.to_bytes(),
-        Err(e) => panic!("Failed to read lambda response: {:?}", e),
+        Err(e) => {
+            warn!("Failed to read the lambda response. The response is rejected: {:?}", e);
+            invocations::finish(&receipt_handle);
+            return Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body(empty())
+                .expect("Failed to create a response");
+        }
     };
 
     let sqs_payload = match String::from_utf8(response.as_ref().to_vec()) {
@@ -61,21 +85,110 @@ pub(crate) async fn handler(req: Request<hyper::body::Incoming>) -> Response<Box
 
     info!("Lambda response: {sqs_payload}");
 
+    // AWS discards the responses that arrive after the timeout
+    if invocations::take_timed_out(&receipt_handle) {
+        warn!("The response arrived after the invocation timed out and was discarded");
+        return Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(empty())
+            .expect("Failed to create a response");
+    }
+
+    session::record(Direction::Response, &receipt_handle, &sqs_payload);
+
+    // the invocation is over even if the response is rejected by the injected fault below
+    let invocation = invocations::finish(&receipt_handle);
+
+    // the response is not forwarded, so the same payload is served on the next invocation
+    let config = get_config();
+    if let Some(resp) = inject_fault(&config) {
+        return resp;
+    }
+
+    if let Some(message_id) = invocation.as_ref().and_then(|v| v.message_id.as_deref()) {
+        info!("Response to SQS message ID: {message_id}");
+    }
+    invocations::clear_reruns(&invocations::rerun_key(&receipt_handle, invocation.as_ref()));
+
+    if let Some(generator) = &config.generator {
+        generator.inspect_response(&sqs_payload);
+    }
+
+    if config.function_url {
+        match function_url_status(&sqs_payload) {
+            Some(status_code) => info!("Function URL response status code: {status_code}"),
+            None => {
+                warn!("Function URL response has no statusCode. Lambda treats it as 200 with the response as the body.")
+            }
+        }
+    }
+
+    // check the response against the contract, if there is one
+    if let Some(validator) = &config.response_schema {
+        if !schema::validate(validator, &sqs_payload, "Response") && config.strict {
+            // treat the invalid response as a lambda error: it is not forwarded and the rerun is blocked
+            warn!("Response rejected in strict mode");
+            block_next_invocation();
+
+            return Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body(empty())
+                .expect("Failed to create a response");
+        }
+    }
+
     // only send responses back to SQS if the request came from SQS
-    if receipt_handle == LOCAL_REQUEST_ID {
+    // events pushed via /local/invoke in local mode are returned to the caller and
+    // the lambda waits for the next pushed event instead of a rerun
+    let is_pushed_event = matches!(config.sources, PayloadSources::Local(_)) && memory::is_in_flight(&receipt_handle);
+    if is_pushed_event {
+        MemoryBackend.send(sqs_payload.clone(), receipt_handle.clone()).await;
+    }
+
+    // local payloads are served one after another until the last one
+    if receipt_handle == LOCAL_REQUEST_ID && !is_pushed_event && has_more_local_payloads(&config) {
+        debug!("Moving to the next local payload");
+    } else if is_pushed_event || receipt_handle == LOCAL_REQUEST_ID {
         // block the next invocation to prevent an infinite loop of reruns
-        if let Ok(mut w) = BLOCK_NEXT_INVOCATION.write() {
-            debug!("Blocking the next invocation");
-            *w = true;
-        } else {
-            error!("Write deadlock on BLOCK_NEXT_INVOCATION. It's a bug");
-        }
-    } else {
-        sqs::send_output(sqs_payload, receipt_handle).await;
+        block_next_invocation();
+    } else if keep_request && matches!(config.sources, PayloadSources::Remote(_)) {
+        sqs::send_output_keeping_request(sqs_payload).await;
+    } else if let Some(sink) = config.message_sink() {
+        sink.send(sqs_payload, receipt_handle).await;
     }
 
+    // the runtime API acknowledges the response with 202 and a status JSON
+    // https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html#runtimes-api-response
     Response::builder()
-        .status(hyper::StatusCode::OK)
-        .body(empty())
+        .status(config.ack_status)
+        .header("content-type", "application/json")
+        .body(full(r#"{"status":"OK"}"#))
         .expect("Failed to create a response")
 }
+
+/// Returns the HTTP status code from `statusCode` field of a Lambda function URL response, if present.
+pub(crate) fn function_url_status(response: &str) -> Option<u16> {
+    serde_json::from_str::<Value>(response)
+        .ok()?
+        .get("statusCode")?
+        .as_u64()
+        .and_then(|v| u16::try_from(v).ok())
+}
+
+/// Advances to the next local payload and returns true if there is one.
+/// Resets to the first payload after the last one so that the file can be rerun from the start.
+pub(crate) fn has_more_local_payloads(config: &Config) -> bool {
+    let payload_count = match &config.sources {
+        PayloadSources::Local(local_config) => local_config.payloads.len(),
+        _ => return false,
+    };
+
+    let next = NEXT_LOCAL_PAYLOAD.load(Ordering::Relaxed) + 1;
+    if next < payload_count {
+        NEXT_LOCAL_PAYLOAD.store(next, Ordering::Relaxed);
+        true
+    } else {
+        NEXT_LOCAL_PAYLOAD.store(0, Ordering::Relaxed);
+        false
+    }
+}
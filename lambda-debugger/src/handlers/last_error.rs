@@ -0,0 +1,31 @@
+use super::{empty, full, LAST_ERROR};
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::Error;
+use hyper::Response;
+use tracing::error;
+
+/// Handles `GET /runtime/last-error` requests by returning the body of the last error reported by the lambda
+/// or 204 if there were no errors.
+/// This is not a part of the Lambda Runtime API. It is meant for scripts and UIs wrapping the emulator.
+pub(crate) async fn handler() -> Response<BoxBody<Bytes, Error>> {
+    let last_error = match LAST_ERROR.read() {
+        Ok(v) => v.clone(),
+        Err(_) => {
+            error!("Read deadlock on LAST_ERROR. It's a bug");
+            None
+        }
+    };
+
+    match last_error {
+        Some(v) => Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(full(v))
+            .expect("Failed to create a response"),
+        None => Response::builder()
+            .status(hyper::StatusCode::NO_CONTENT)
+            .body(empty())
+            .expect("Failed to create a response"),
+    }
+}
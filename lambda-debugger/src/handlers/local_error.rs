@@ -0,0 +1,48 @@
+use super::memory_queue::read_payload;
+use super::{empty, full};
+use crate::config::PayloadSources;
+use crate::get_config;
+use crate::sqs;
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::{Error, Request, Response};
+use tracing::{info, warn};
+
+/// Handles `POST /local/error` requests by sending the error from the request body to the response queue
+/// in the same format as lambda errors forwarded with LAMBDA_EMULATOR_ECHO_ERRORS,
+/// to test how the proxy handles errors without making the lambda fail.
+/// Only available in debug mode with remote payloads. Returns 403 if the debug mode is off.
+pub(crate) async fn handler(req: Request<hyper::body::Incoming>) -> Response<BoxBody<Bytes, Error>> {
+    // the path must not fall through to the lambda error handler, so it is matched regardless of the debug mode
+    let config = get_config();
+    if !config.debug {
+        warn!("Error rejected: POST /local/error is only available with LAMBDA_EMULATOR_DEBUG=true");
+        return Response::builder()
+            .status(hyper::StatusCode::FORBIDDEN)
+            .body(full(
+                "Set LAMBDA_EMULATOR_DEBUG=true env var to enable POST /local/error",
+            ))
+            .expect("Failed to create a response");
+    }
+
+    if !matches!(config.sources, PayloadSources::Remote(_)) {
+        warn!("Error rejected: POST /local/error needs a response queue");
+        return Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(full("No response queue"))
+            .expect("Failed to create a response");
+    }
+
+    let lambda_error = match read_payload(req).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+
+    info!("Sending a manual error to the response queue: {lambda_error}");
+    sqs::send_error(lambda_error).await;
+
+    Response::builder()
+        .status(hyper::StatusCode::ACCEPTED)
+        .body(empty())
+        .expect("Failed to create a response")
+}
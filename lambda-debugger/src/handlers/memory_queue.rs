@@ -0,0 +1,128 @@
+use super::lambda_response::function_url_status;
+use super::{empty, full};
+use crate::get_config;
+use crate::headers;
+use crate::memory::{self, InvocationResult};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::body::Bytes;
+use hyper::Error;
+use hyper::{Request, Response};
+use tracing::{info, warn};
+
+/// Handles `POST /local/enqueue` requests with events to be passed onto the lambda.
+/// Responds with the request ID assigned to the event or 503 if the queue is full.
+pub(crate) async fn enqueue(req: Request<hyper::body::Incoming>) -> Response<BoxBody<Bytes, Error>> {
+    let payload = match read_payload(req).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+
+    match memory::enqueue(payload, false) {
+        Some((request_id, _)) => accepted(request_id),
+        None => queue_full(),
+    }
+}
+
+/// Handles `POST /local/invoke` requests with events to be passed onto the lambda.
+/// Works the same way as `/local/enqueue`, but with `?wait=true` it waits for the lambda to process the event
+/// and responds with the lambda response or 500 with the lambda error.
+pub(crate) async fn invoke(req: Request<hyper::body::Incoming>) -> Response<BoxBody<Bytes, Error>> {
+    let wait = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .any(|v| v == "wait=true" || v == "wait=1");
+
+    let payload = match read_payload(req).await {
+        Ok(v) => v,
+        Err(resp) => return resp,
+    };
+
+    let (request_id, receiver) = match memory::enqueue(payload, wait) {
+        Some(v) => v,
+        None => return queue_full(),
+    };
+
+    let receiver = match receiver {
+        Some(v) => v,
+        None => return accepted(request_id),
+    };
+
+    info!("Waiting for the lambda to process {request_id}");
+
+    let (status, body) = match receiver.await {
+        Ok(InvocationResult::Response(v)) => (response_status(&v), v),
+        Ok(InvocationResult::Error(v)) => (hyper::StatusCode::INTERNAL_SERVER_ERROR, v),
+        Err(_) => panic!("The waiting channel for {request_id} was dropped. It's a bug."),
+    };
+
+    Response::builder()
+        .status(status)
+        .header(headers::REQUEST_ID, request_id)
+        .body(full(body))
+        .expect("Failed to create a response")
+}
+
+/// Returns the status code for the lambda response: the `statusCode` of function URL responses in `--function-url`
+/// mode or 200 otherwise.
+fn response_status(response: &str) -> hyper::StatusCode {
+    if !get_config().function_url {
+        return hyper::StatusCode::OK;
+    }
+
+    function_url_status(response)
+        .and_then(|v| hyper::StatusCode::from_u16(v).ok())
+        .unwrap_or(hyper::StatusCode::OK)
+}
+
+/// Handles `GET /local/dequeue` requests by returning the oldest lambda response
+/// or 204 if there are no responses.
+pub(crate) async fn dequeue() -> Response<BoxBody<Bytes, Error>> {
+    match memory::dequeue() {
+        Some((request_id, response)) => Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(headers::REQUEST_ID, request_id)
+            .body(full(response))
+            .expect("Failed to create a response"),
+        None => Response::builder()
+            .status(hyper::StatusCode::NO_CONTENT)
+            .body(empty())
+            .expect("Failed to create a response"),
+    }
+}
+
+/// Reads the event from the request body or returns 400 if the body is not a valid UTF-8 string.
+pub(crate) async fn read_payload(
+    req: Request<hyper::body::Incoming>,
+) -> Result<String, Response<BoxBody<Bytes, Error>>> {
+    let body = match req.into_body().collect().await {
+        Ok(v) => v.to_bytes(),
+        Err(e) => panic!("Failed to read the event: {:?}", e),
+    };
+
+    String::from_utf8(body.to_vec()).map_err(|e| {
+        warn!("Event rejected: non-UTF-8 payload. {:?}", e);
+        Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(full("Non-UTF-8 payload"))
+            .expect("Failed to create a response")
+    })
+}
+
+/// Responds with the request ID assigned to the queued event.
+fn accepted(request_id: String) -> Response<BoxBody<Bytes, Error>> {
+    Response::builder()
+        .status(hyper::StatusCode::ACCEPTED)
+        .header(headers::REQUEST_ID, request_id.as_str())
+        .body(full(request_id))
+        .expect("Failed to create a response")
+}
+
+/// Responds with 503 when the event could not be queued.
+fn queue_full() -> Response<BoxBody<Bytes, Error>> {
+    Response::builder()
+        .status(hyper::StatusCode::SERVICE_UNAVAILABLE)
+        .body(full("The request queue is full"))
+        .expect("Failed to create a response")
+}
@@ -1,9 +1,20 @@
+use crate::config::Config;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::body::Bytes;
-use std::sync::RwLock;
+use hyper::Response;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
 
+pub(crate) mod dropped;
 pub(crate) mod lambda_error;
 pub(crate) mod lambda_response;
+pub(crate) mod last_error;
+pub(crate) mod local_error;
+pub(crate) mod memory_queue;
 pub(crate) mod next_invocation;
 
 /// A request ID substitute for local file payloads.
@@ -13,8 +24,81 @@ pub(crate) const LOCAL_REQUEST_ID: &str = "local-request-id";
 /// Is set to TRUE if the next invocation will be using the same payload resulting
 /// in an infinite loop. It happens with SUCCESS responses for local payloads and all ERROR responses.
 /// It is set while processing the response (success or error).
-/// Once an invocation is blocked, it is reset to FALSE to let the next invocation can go ahead. 
-pub(crate) static BLOCK_NEXT_INVOCATION: RwLock<bool> = RwLock::new(false);
+/// Once an invocation is blocked, it is reset to FALSE to let the next invocation can go ahead.
+static BLOCK_NEXT_INVOCATION: AtomicBool = AtomicBool::new(false);
+
+/// Wakes up the invocations blocked by BLOCK_NEXT_INVOCATION, e.g. when the emulator shuts down.
+pub(crate) static UNBLOCK_INVOCATIONS: Notify = Notify::const_new();
+
+/// Is set to TRUE before UNBLOCK_INVOCATIONS is notified on shutdown, so that the invocations
+/// that get blocked after the notification do not wait for it. It is reset when the emulator starts.
+pub(crate) static INVOCATIONS_RELEASED: AtomicBool = AtomicBool::new(false);
+
+/// Sets BLOCK_NEXT_INVOCATION flag to block the next invocation.
+pub(crate) fn block_next_invocation() {
+    debug!("Blocking the next invocation");
+    BLOCK_NEXT_INVOCATION.store(true, Ordering::SeqCst);
+}
+
+/// Resets BLOCK_NEXT_INVOCATION flag and returns its previous value.
+pub(crate) fn take_block_next_invocation() -> bool {
+    BLOCK_NEXT_INVOCATION.swap(false, Ordering::SeqCst)
+}
+
+/// The index of the local payload to be served on the next invocation.
+/// It advances after each successful response and is reset after the last payload.
+pub(crate) static NEXT_LOCAL_PAYLOAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Is set to TRUE until the first invocation is served, to delay it by LAMBDA_EMULATOR_STARTUP_DELAY_MS.
+pub(crate) static FIRST_INVOCATION: AtomicBool = AtomicBool::new(true);
+
+/// The body of the last error reported by the lambda, if any.
+/// It is kept until the next error replaces it and is served via `GET /runtime/last-error`.
+pub(crate) static LAST_ERROR: RwLock<Option<String>> = RwLock::new(None);
+
+/// The randomizer for fault injection. It is seeded from LAMBDA_EMULATOR_ERROR_SEED, if set,
+/// to make the sequence of synthetic failures reproducible.
+/// It is created on first use and reset when the emulator starts or reloads its config.
+static FAULT_RNG: Mutex<Option<StdRng>> = Mutex::new(None);
+
+/// Drops the randomizer for fault injection, so that the next one is seeded from the current config
+/// and the sequence of failures starts over.
+pub(crate) fn reset_fault_rng() {
+    if let Ok(mut rng) = FAULT_RNG.lock() {
+        *rng = None;
+    }
+}
+
+/// Returns a synthetic failure response if one should be injected as per LAMBDA_EMULATOR_ERROR_RATE
+/// or None if the request should be processed normally.
+pub(crate) fn inject_fault(config: &Config) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let error_rate = config.error_rate?;
+
+    let fail = match FAULT_RNG.lock() {
+        Ok(mut rng) => {
+            rng.get_or_insert_with(|| match config.error_seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            })
+            .gen::<f64>()
+                < error_rate
+        }
+        Err(_) => false, // the lock can only be poisoned by a panic while generating a number, which is not possible
+    };
+
+    if !fail {
+        return None;
+    }
+
+    warn!("Injected fault: responding with 500");
+
+    Some(
+        Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(full("Injected fault"))
+            .expect("Failed to create a response"),
+    )
+}
 
 /// Returns an empty response body.
 pub(crate) fn empty() -> BoxBody<Bytes, hyper::Error> {
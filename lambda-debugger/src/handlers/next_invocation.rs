@@ -1,90 +1,492 @@
-use super::{full, BLOCK_NEXT_INVOCATION, LOCAL_REQUEST_ID};
-use crate::config::PayloadSources;
-use crate::sqs;
-use crate::CONFIG;
+use super::lambda_response::has_more_local_payloads;
+use super::{
+    block_next_invocation, full, inject_fault, take_block_next_invocation, FIRST_INVOCATION, INVOCATIONS_RELEASED,
+    LOCAL_REQUEST_ID, NEXT_LOCAL_PAYLOAD, UNBLOCK_INVOCATIONS,
+};
+use crate::cloudevents;
+use crate::config::{Config, PayloadSources};
+use crate::emulator::stop_with_error;
+use crate::get_config;
+use crate::invocations;
+use crate::memory::{self, MemoryBackend};
+use crate::mutate;
+use crate::queues::MessageSource;
+use crate::schema;
+use crate::session::{self, Direction};
+use crate::sqs::{self, SqsMessage};
+use crate::RuntimeHeaders;
 use http_body_util::combinators::BoxBody;
 use hyper::body::Bytes;
+use hyper::header::HeaderValue;
 use hyper::Error;
 use hyper::Response;
+use rand::Rng;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
 use tokio::time::{sleep, Duration};
 use tracing::{error, info, warn};
 
+/// The header with a key that stays the same for all deliveries of the same event,
+/// so that idempotent handlers can detect the reruns and re-deliveries.
+const IDEMPOTENCY_KEY_HEADER: &str = "x-emulator-idempotency-key";
+
 /// Handles _next invocation_ request from the local lambda.
 /// It blocks on SQS and waits indefinitely for the next SQS message to arrive.
 /// The first message in the queue is passed back onto the local lambda.
 /// See https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html#runtimes-api-next
 pub(crate) async fn handler() -> Response<BoxBody<Bytes, Error>> {
+    // check if there is a payload file name in the command line arguments
+    let config = get_config();
+    let is_local = matches!(config.sources, PayloadSources::Local(_));
+    let is_memory = matches!(config.sources, PayloadSources::Memory(_));
+
+    // the lambda should have responded to the previous invocation before asking for the next one
+    invocations::abandon_outstanding();
+
     // check if the current invocation is a re-run and should be blocked
-    block_if_rerun().await;
+    // events pushed via /local/invoke are served ahead of the payload file
+    let pushed_event = if block_if_rerun(is_local, is_memory).await {
+        match MemoryBackend.next().await {
+            Ok(v) => Some(v),
+            Err(e) => return receive_failed(e),
+        }
+    } else if is_local {
+        memory::try_get_input()
+    } else {
+        None
+    };
 
-    // check if there is a payload file name in the command line arguments
-    let config = CONFIG.get().await;
-
-    // return local payload from the file if was provided
-    if let PayloadSources::Local(local_config) = &config.sources {
-        info!("Lambda request: sending payload from file");
-
-        return Response::builder()
-            .status(hyper::StatusCode::OK)
-            .header("lambda-runtime-aws-request-id", LOCAL_REQUEST_ID)
-            .header("lambda-runtime-deadline-ms", "2035313041000") // 2034
-            .header("lambda-runtime-invoked-function-arn", "from-local-payload")
-            .header(
-                "lambda-runtime-trace-id",
-                "Root=0-00000000-000000000000000000000000;Parent=0000000000000000;Sampled=0;Lineage=00000000:0",
-            )
-            .body(full(local_config.payload.clone()))
-            .expect("Failed to create a response");
+    // give the lambda time to finish its initialization before the very first invocation
+    let is_first_invocation = FIRST_INVOCATION.swap(false, Ordering::Relaxed);
+    if let (Some(delay), true) = (config.startup_delay, is_first_invocation) {
+        info!("Delaying the first invocation by {}ms", delay.as_millis());
+        sleep(delay).await;
+    }
+
+    // simulate a slow cold start or a congested queue
+    if let Some(delay) = config.inject_delay {
+        info!("Delaying the invocation by {}ms", delay.as_millis());
+        sleep(delay).await;
+    }
+
+    // fail before the payload is fetched so that it is not lost
+    if let Some(resp) = inject_fault(&config) {
+        return resp;
+    }
+
+    // return local payload from the file if was provided and there are no pushed events
+    if let (PayloadSources::Local(local_config), None) = (&config.sources, &pushed_event) {
+        // the index may be out of range if the config was reloaded with fewer payloads
+        let payload_count = local_config.payloads.len();
+        let idx = match NEXT_LOCAL_PAYLOAD.load(Ordering::Relaxed) {
+            v if v < payload_count => v,
+            _ => 0,
+        };
+
+        // space out the events to simulate a realistic arrival cadence, including the reruns of the same payload
+        if let (Some(interval), false) = (config.interval, is_first_invocation) {
+            info!("Waiting {}ms before the next payload", interval.as_millis());
+            sleep(interval).await;
+        }
+
+        let source = if local_config.command.is_some() {
+            "command"
+        } else {
+            "file"
+        };
+
+        let (payload, label) = if let Some(command) = &local_config.command {
+            // the lambda gets an error instead of a connection reset, so that it does not retry straight away
+            let payload = match run_command(command).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("{e}");
+                    return Response::builder()
+                        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(full("Failed to generate the payload"))
+                        .expect("Failed to create a response");
+                }
+            };
+            info!("Lambda request: sending payload from command");
+            (payload, "Request".to_owned())
+        } else if payload_count > 1 {
+            info!(
+                "Lambda request: sending payload {} of {payload_count} from file",
+                idx + 1
+            );
+            (local_config.payloads[idx].clone(), format!("Request #{}", idx + 1))
+        } else {
+            info!("Lambda request: sending payload from file");
+            (local_config.payloads[idx].clone(), "Request".to_owned())
+        };
+        // the key is computed before the payload is mutated to stay the same for reruns of the same event
+        let idempotency_key = payload_hash(&payload);
+        let payload = mutate::apply(&payload, &config.mutations);
+        let payload = match &config.transform {
+            Some(transform) => transform.apply(&payload),
+            None => payload,
+        };
+
+        // the invalid event never reaches the lambda: the next payload is served instead or the invocation is blocked
+        if let Some(validator) = &config.request_schema {
+            if !schema::validate(validator, &payload, &label) && config.strict {
+                warn!("{label} rejected in strict mode");
+                if local_config.command.is_some() || !has_more_local_payloads(&config) {
+                    block_next_invocation();
+                }
+                return Box::pin(handler()).await;
+            }
+        }
+
+        let payload = if config.cloudevents {
+            cloudevents::wrap(&payload, source)
+        } else {
+            payload
+        };
+
+        // the span is only entered by the synchronous code, so it does not leak into other tasks across awaits
+        let span = invocations::start(LOCAL_REQUEST_ID, None);
+        return span.in_scope(|| {
+            let headers = RuntimeHeaders {
+                request_id: LOCAL_REQUEST_ID.to_owned(),
+                deadline_ms: deadline_ms(&config, 2035313041000), // 2034
+                invoked_function_arn: "from-local-payload".to_owned(),
+                trace_id: trace_id(&config, None),
+                client_context: config.client_context.clone(),
+                cognito_identity: config.cognito_identity.clone(),
+            };
+
+            session::record(Direction::Event, &headers.request_id, &payload);
+            if config.enforce_timeout {
+                invocations::enforce_deadline(&headers.request_id, headers.deadline_ms);
+            }
+
+            with_headers(full(payload), &headers, &config, source, None, &idempotency_key)
+        });
+    };
+
+    let source = match (&config.sources, &pushed_event) {
+        (PayloadSources::Memory(_), _) => "memory",
+        (_, Some(_)) => "local-invoke",
+        _ => "sqs",
     };
 
     // get the next SQS message or wait for it to arrive
     // this call will block until a message is available
-    let sqs_message = sqs::get_input().await;
+    let mut sqs_message: SqsMessage = match pushed_event {
+        Some(v) => v,
+        None => {
+            let message = config
+                .message_source()
+                .expect("Invalid config: no message source for a non-local payload. It's a bug.")
+                .next()
+                .await;
+            match message {
+                Ok(v) => v,
+                Err(e) => return receive_failed(e),
+            }
+        }
+    };
 
-    info!("Lambda request:\n{}", sqs_message.payload);
+    // SQS re-delivers the message with the same message ID, but a different receipt handle
+    // the events from the memory queue have no message ID, but keep the request ID they were enqueued with,
+    // so that the same payload enqueued twice is treated as two different events
+    let idempotency_key = match &sqs_message.message_id {
+        Some(v) => v.clone(),
+        None => sqs_message.receipt_handle.clone(),
+    };
 
-    Response::builder()
-        .status(hyper::StatusCode::OK)
-        .header("lambda-runtime-aws-request-id", sqs_message.receipt_handle)
-        .header("lambda-runtime-deadline-ms", sqs_message.ctx.deadline)
-        .header(
-            "lambda-runtime-invoked-function-arn",
-            sqs_message.ctx.invoked_function_arn,
-        )
-        .header(
-            "lambda-runtime-trace-id",
-            sqs_message.ctx.xray_trace_id.unwrap_or_else(|| {
-                "Root=0-00000000-000000000000000000000000;Parent=0000000000000000;Sampled=0;Lineage=00000000:0"
-                    .to_owned()
-            }),
+    if let Some(transform) = &config.transform {
+        sqs_message.payload = transform.apply(&sqs_message.payload);
+    }
+
+    if let Some(validator) = &config.request_schema {
+        if !schema::validate(validator, &sqs_message.payload, "Request") && config.strict {
+            reject_request(source, &sqs_message.receipt_handle).await;
+            return Box::pin(handler()).await;
+        }
+    }
+
+    let span = invocations::start(&sqs_message.receipt_handle, Some(&sqs_message));
+    span.in_scope(|| {
+        info!("Lambda request:\n{}", loggable_payload(&sqs_message.payload, &config));
+        if let Some(message_id) = &sqs_message.message_id {
+            info!("SQS message ID: {message_id}");
+        }
+
+        // mobile SDK fields are passed through from the original request context, if present
+        let client_context = sqs_message
+            .ctx
+            .client_context
+            .as_ref()
+            .and_then(|v| serde_json::to_string(v).ok())
+            .or_else(|| config.client_context.clone());
+        let cognito_identity = sqs_message
+            .ctx
+            .identity
+            .as_ref()
+            .and_then(|v| serde_json::to_string(v).ok())
+            .or_else(|| config.cognito_identity.clone());
+
+        let message_id = sqs_message.message_id;
+
+        let headers = RuntimeHeaders {
+            request_id: sqs_message.receipt_handle,
+            deadline_ms: deadline_ms(&config, sqs_message.ctx.deadline),
+            invoked_function_arn: sqs_message.ctx.invoked_function_arn,
+            trace_id: trace_id(&config, sqs_message.ctx.xray_trace_id),
+            client_context,
+            cognito_identity,
+        };
+
+        let payload = if config.cloudevents {
+            cloudevents::wrap(&sqs_message.payload, source)
+        } else {
+            sqs_message.payload
+        };
+
+        session::record(Direction::Event, &headers.request_id, &payload);
+        if config.enforce_timeout {
+            invocations::enforce_deadline(&headers.request_id, headers.deadline_ms);
+        }
+
+        with_headers(
+            full(payload),
+            &headers,
+            &config,
+            source,
+            message_id.as_deref(),
+            &idempotency_key,
         )
-        .body(full(sqs_message.payload))
+    })
+}
+
+/// Answers the lambda with an error if no more messages can be received and shuts down the emulator
+/// because the lambda would get the same error on every retry.
+fn receive_failed(error: String) -> Response<BoxBody<Bytes, Error>> {
+    stop_with_error(error);
+    Response::builder()
+        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+        .body(full("Failed to get the next event"))
         .expect("Failed to create a response")
 }
 
-/// Checks BLOCK_NEXT_INVOCATION global flag and
-/// blocks the current thread if the current invocation should be blocked.
-async fn block_if_rerun() {
-    // create a local copy of the blocking flag
-    let block = if let Ok(block) = BLOCK_NEXT_INVOCATION.read() {
-        *block
-    } else {
-        error!("Read deadlock on BLOCK_NEXT_INVOCATION. It's a bug");
-        false
+/// Returns the error to the caller of the event that failed the schema validation in strict mode
+/// instead of sending the event to the lambda, so that the caller is not left waiting.
+/// SQS messages are deleted because a rerun would be rejected the same way.
+async fn reject_request(source: &'static str, receipt_handle: &str) {
+    warn!("Request rejected in strict mode");
+    let error =
+        r#"{"errorType":"RequestRejected","errorMessage":"The request does not match LAMBDA_EMULATOR_REQUEST_SCHEMA"}"#;
+
+    match source {
+        "sqs" => sqs::reject_request(error.to_owned(), receipt_handle).await,
+        _ => memory::send_error(error.to_owned(), receipt_handle),
+    }
+
+    // the lambda waits for the next pushed event as it does after a response to a pushed event
+    if source == "local-invoke" {
+        block_next_invocation();
+    }
+}
+
+/// Sets the Sampled flag of the X-Ray trace header as per LAMBDA_EMULATOR_XRAY_SAMPLED, if it is set.
+/// A new trace header is generated if the event has none because the X-Ray SDK needs a valid trace ID
+/// to emit segments. The trace header is returned as-is if the env var is not set.
+fn trace_id(config: &Config, trace_id: Option<String>) -> Option<String> {
+    let Some(sampled) = config.xray_sampled else {
+        return trace_id;
     };
+    let sampled = format!("Sampled={}", sampled as u8);
 
-    // unblock the next invocation
-    if block {
-        if let Ok(mut w) = BLOCK_NEXT_INVOCATION.write() {
-            *w = false;
+    let mut parts = Vec::new();
+    let mut has_sampled = false;
+    for part in trace_id.unwrap_or_else(new_trace_id).split(';') {
+        if part.starts_with("Sampled=") {
+            has_sampled = true;
+            parts.push(sampled.clone());
         } else {
-            error!("Write deadlock on BLOCK_NEXT_INVOCATION. It's a bug");
+            parts.push(part.to_owned());
+        }
+    }
+    if !has_sampled {
+        parts.push(sampled);
+    }
+
+    Some(parts.join(";"))
+}
+
+/// Returns a new X-Ray trace header without the Sampled flag, e.g.
+/// `Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8`.
+fn new_trace_id() -> String {
+    let mut rng = rand::thread_rng();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or_default();
+
+    format!(
+        "Root=1-{now:08x}-{:024x};Parent={:016x}",
+        rng.gen::<u128>() >> 32,
+        rng.gen::<u64>()
+    )
+}
+
+/// Returns the SHA-256 hash of the payload as a hex string to identify the events from the payload file.
+fn payload_hash(payload: &str) -> String {
+    hex::encode(Sha256::digest(payload.as_bytes()))
+}
+
+/// Returns the deadline for the invocation in milliseconds since the epoch.
+/// It is counted from now if LAMBDA_EMULATOR_DEADLINE_OVERRIDE_SECS is set or is the deadline of the request otherwise.
+fn deadline_ms(config: &Config, deadline: u64) -> u64 {
+    let Some(deadline_override) = config.deadline_override else {
+        return deadline;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is before the epoch");
+
+    (now + deadline_override).as_millis() as u64
+}
+
+/// Returns the part of the payload at LAMBDA_EMULATOR_LOG_POINTER for logging
+/// or the whole payload if the pointer is not set or there is nothing at the pointer.
+fn loggable_payload<'a>(payload: &'a str, config: &Config) -> Cow<'a, str> {
+    let Some(pointer) = &config.log_pointer else {
+        return Cow::Borrowed(payload);
+    };
+
+    match serde_json::from_str::<Value>(payload)
+        .ok()
+        .and_then(|v| v.pointer(pointer).cloned())
+    {
+        Some(v) => Cow::Owned(format!("{pointer}: {v}")),
+        None => Cow::Borrowed(payload),
+    }
+}
+
+/// Runs the shell command and returns its stdout as the payload.
+/// Returns the error with the stderr of the command if it fails or prints nothing
+/// because there is nothing to send to the lambda.
+async fn run_command(command: &str) -> Result<String, String> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(command).output().await
+    } else {
+        Command::new("sh").arg("-c").arg(command).output().await
+    };
+
+    let output = match output {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Failed to run `{command}`\n{:?}", e)),
+    };
+
+    if !output.status.success() {
+        return Err(format!(
+            "`{command}` failed with {}\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let payload = match String::from_utf8(output.stdout) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("Non-UTF-8 output from `{command}`\n{:?}", e)),
+    };
+
+    if payload.trim().is_empty() {
+        return Err(format!(
+            "`{command}` printed no payload to stdout\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(payload)
+}
+
+/// Creates a 200 response with the payload for the lambda and the runtime API headers.
+/// In debug mode it also adds non-standard `x-emulator-source`, `x-emulator-queue` and `x-emulator-message-id` headers
+/// to show where the payload came from. The runtime client ignores them.
+fn with_headers(
+    body: BoxBody<Bytes, Error>,
+    headers: &RuntimeHeaders,
+    config: &Config,
+    source: &'static str,
+    message_id: Option<&str>,
+    idempotency_key: &str,
+) -> Response<BoxBody<Bytes, Error>> {
+    let mut resp = Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(body)
+        .expect("Failed to create a response");
+    *resp.headers_mut() = headers.to_headers();
+
+    if let Ok(idempotency_key) = HeaderValue::from_str(idempotency_key) {
+        resp.headers_mut().insert(IDEMPOTENCY_KEY_HEADER, idempotency_key);
+    }
+
+    if config.debug {
+        resp.headers_mut()
+            .insert("x-emulator-source", HeaderValue::from_static(source));
+
+        let queue = match &config.sources {
+            PayloadSources::Remote(remote_config) if source == "sqs" => Some(remote_config.request_queue_url.as_str()),
+            _ if source == "memory" || source == "local-invoke" => Some("in-memory"),
+            _ => None,
+        };
+        if let Some(queue) = queue.and_then(|v| HeaderValue::from_str(v).ok()) {
+            resp.headers_mut().insert("x-emulator-queue", queue);
+        }
+        if let Some(message_id) = message_id.and_then(|v| HeaderValue::from_str(v).ok()) {
+            resp.headers_mut().insert("x-emulator-message-id", message_id);
         }
     }
 
-    // sleep for a month to prevent a rerun
+    resp
+}
+
+/// Checks BLOCK_NEXT_INVOCATION global flag and
+/// blocks the current thread if the current invocation should be blocked.
+/// In local and memory modes it returns true instead of blocking so that the caller can wait for an event
+/// pushed via `/local/invoke` or `/local/enqueue`.
+async fn block_if_rerun(is_local: bool, is_memory: bool) -> bool {
+    // read and reset the blocking flag to let the invocation after this one go ahead
+    let block = take_block_next_invocation();
+
+    if block && is_local {
+        warn!("Restart your lambda for a rerun or push an event via POST /local/invoke");
+        return true;
+    }
+
+    // events from the memory queue are not rerun, so the next queued event can be served
+    if block && is_memory {
+        info!("Waiting for the next event pushed via POST /local/enqueue");
+        return true;
+    }
+
+    // sleep for a month to prevent a rerun or until woken up, e.g. on shutdown
     if block {
         warn!("Restart your lambda for a rerun");
-        sleep(Duration::from_secs(31563000)).await;
+
+        // the waiter is registered before the flag is checked, so a shutdown in between is not missed
+        let released = UNBLOCK_INVOCATIONS.notified();
+        tokio::pin!(released);
+        released.as_mut().enable();
+        if INVOCATIONS_RELEASED.load(Ordering::SeqCst) {
+            info!("Blocked invocation released");
+            return false;
+        }
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(31563000)) => {}
+            _ = released => info!("Blocked invocation released"),
+        }
     }
+
+    false
 }
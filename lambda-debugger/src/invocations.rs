@@ -0,0 +1,321 @@
+use crate::config::PayloadSources;
+use crate::emulator;
+use crate::handlers::{block_next_invocation, LOCAL_REQUEST_ID};
+use crate::memory;
+use crate::sqs::{self, SqsMessage};
+use crate::{get_config, HandlerError};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+
+/// An invocation served to the lambda that has not been responded to yet.
+pub(crate) struct Invocation {
+    /// When the payload was sent to the lambda
+    pub served_at: Instant,
+    /// When the SQS message becomes visible in the request queue again unless it is deleted by then.
+    /// None for messages that did not come from SQS.
+    pub visible_until: Option<Instant>,
+    /// The SQS message ID to correlate the invocation with the message in the AWS console
+    pub message_id: Option<String>,
+    /// Groups the log lines of the invocation from the moment it is served until the lambda responds
+    pub span: Span,
+}
+
+/// Invocations served to the lambda, keyed by the request ID, which is the receipt handle for SQS messages.
+static INVOCATIONS: OnceLock<Mutex<HashMap<String, Invocation>>> = OnceLock::new();
+
+/// The number of reruns of failed invocations, keyed by the SQS message ID or the request ID for other payloads.
+/// The receipt handle changes every time SQS re-delivers a message, but the message ID stays the same.
+static RERUNS: OnceLock<Mutex<HashMap<String, u32>>> = OnceLock::new();
+
+/// Request IDs of the invocations that timed out as per LAMBDA_EMULATOR_ENFORCE_TIMEOUT.
+/// Late responses and errors to them are discarded.
+static TIMED_OUT: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Max lambda timeout. Longer deadlines, e.g. the far-future deadline of local payloads, are not enforced.
+const MAX_FUNCTION_TIMEOUT: Duration = Duration::from_secs(900);
+
+/// Returns the invocation registry, creating it if needed.
+fn invocations() -> &'static Mutex<HashMap<String, Invocation>> {
+    INVOCATIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Records the invocation served to the lambda and returns its tracing span.
+/// The message is None for local payloads.
+/// The span carries the SQS message ID, if known, because receipt handles are too long to be readable in the logs.
+pub(crate) fn start(request_id: &str, message: Option<&SqsMessage>) -> Span {
+    let message_id = message.and_then(|v| v.message_id.clone());
+    let span = info_span!("invocation", id = message_id.as_deref().unwrap_or(request_id));
+    let served_at = Instant::now();
+    let visible_until = message.and_then(|v| v.visible_until);
+
+    // local payloads reuse the same request ID, so it may have timed out before
+    if let Ok(mut timed_out) = TIMED_OUT.lock() {
+        if let Some(timed_out) = timed_out.as_mut() {
+            timed_out.remove(request_id);
+        }
+    }
+
+    if let Ok(mut invocations) = invocations().lock() {
+        invocations.insert(
+            request_id.to_owned(),
+            Invocation {
+                served_at,
+                visible_until,
+                message_id,
+                span: span.clone(),
+            },
+        );
+    }
+
+    // SQS messages are orphaned when they become visible again because SQS re-delivers them
+    let orphaned_at = match (visible_until, get_config().orphan_timeout) {
+        (_, None) => None,
+        (Some(visible_until), Some(_)) => Some(visible_until),
+        (None, Some(timeout)) => Some(served_at + timeout),
+    };
+    if let Some(orphaned_at) = orphaned_at {
+        watch(
+            request_id.to_owned(),
+            served_at,
+            orphaned_at,
+            visible_until.is_some(),
+            span.clone(),
+        );
+    }
+
+    span
+}
+
+/// Warns if the lambda has not responded to the invocation by `orphaned_at`, e.g. because it crashed
+/// after fetching the event. Invocations are identified by the request ID and the time they were served
+/// because local payloads reuse the same request ID.
+fn watch(request_id: String, served_at: Instant, orphaned_at: Instant, is_sqs: bool, span: Span) {
+    let task = async move {
+        sleep_until(orphaned_at).await;
+
+        let is_orphaned = invocations()
+            .lock()
+            .is_ok_and(|v| v.get(&request_id).is_some_and(|v| v.served_at == served_at));
+        if !is_orphaned {
+            return;
+        }
+
+        let redelivery = if is_sqs {
+            "\nThe message is visible in the request queue again and SQS will re-deliver it."
+        } else {
+            ""
+        };
+        warn!(
+            "No response or error from the lambda in {}s. It may have crashed or be paused at a breakpoint.{redelivery}",
+            served_at.elapsed().as_secs()
+        );
+    };
+
+    tokio::spawn(task.instrument(span));
+}
+
+/// Fails the invocation if the lambda has not responded to it by the deadline, same as AWS does:
+/// logs `Task timed out after N seconds`, returns the timeout error to the local caller waiting for it
+/// and makes the SQS message visible in the request queue again.
+/// The timeout counts as a lambda error: the invocation is rerun up to LAMBDA_EMULATOR_MAX_RERUNS times
+/// and the next invocation is blocked after that, so that a local payload is not served again in a loop.
+/// Deadlines more than 15 min away are not enforced because no lambda can run that long.
+pub(crate) fn enforce_deadline(request_id: &str, deadline_ms: u64) {
+    let Some((served_at, span)) = invocations()
+        .lock()
+        .ok()
+        .and_then(|v| v.get(request_id).map(|v| (v.served_at, v.span.clone())))
+    else {
+        return;
+    };
+
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or_default();
+    let timeout = Duration::from_millis(deadline_ms.saturating_sub(now_ms));
+    if timeout > MAX_FUNCTION_TIMEOUT {
+        debug!("The deadline is not enforced: {}s away", timeout.as_secs());
+        return;
+    }
+
+    let request_id = request_id.to_owned();
+    let task = async move {
+        sleep_until(served_at + timeout).await;
+
+        let invocation = match invocations().lock() {
+            Ok(mut invocations) if invocations.get(&request_id).is_some_and(|v| v.served_at == served_at) => {
+                invocations.remove(&request_id)
+            }
+            _ => None,
+        };
+        let Some(invocation) = invocation else {
+            return;
+        };
+
+        if let Ok(mut timed_out) = TIMED_OUT.lock() {
+            timed_out.get_or_insert_with(HashSet::new).insert(request_id.clone());
+        }
+
+        let error_message = format!("Task timed out after {:.2} seconds", timeout.as_secs_f64());
+        warn!("{error_message}");
+
+        // a timeout is handled like a lambda error because the late response is discarded
+        // events from the memory queue are not rerun because their outcome goes back to the caller
+        let config = get_config();
+        let is_in_flight = memory::is_in_flight(&request_id);
+        let rerun = match config.max_reruns > 0 && !is_in_flight {
+            true => try_rerun(&rerun_key(&request_id, Some(&invocation)), config.max_reruns),
+            false => None,
+        };
+
+        if let Some(rerun) = rerun {
+            info!("Rerunning the timed out invocation: {rerun} of {}", config.max_reruns);
+        } else if !config.block_on_error {
+            debug!("Not blocking the next invocation as per LAMBDA_EMULATOR_NO_BLOCK_ON_ERROR");
+        } else {
+            // block the next invocation to prevent an infinite loop of timeouts
+            block_next_invocation();
+        }
+
+        if is_in_flight {
+            let error = serde_json::json!({ "errorType": "Sandbox.Timedout", "errorMessage": error_message });
+            memory::send_error(error.to_string(), &request_id);
+        } else if matches!(config.sources, PayloadSources::Remote(_)) && request_id != LOCAL_REQUEST_ID {
+            sqs::release_message(&request_id).await;
+        }
+
+        // the invocation is over unless it is rerun
+        if config.once && rerun.is_none() {
+            emulator::finish_one_shot(Err(HandlerError {
+                error_type: Some("Sandbox.Timedout".to_owned()),
+                error_message: Some(error_message),
+                body: String::new(),
+            }));
+        }
+    };
+
+    tokio::spawn(task.instrument(span));
+}
+
+/// Returns true if the invocation timed out as per LAMBDA_EMULATOR_ENFORCE_TIMEOUT and forgets it,
+/// so that the late response or error can be discarded.
+pub(crate) fn take_timed_out(request_id: &str) -> bool {
+    match TIMED_OUT.lock() {
+        Ok(mut timed_out) => timed_out.as_mut().is_some_and(|v| v.remove(request_id)),
+        Err(_) => false,
+    }
+}
+
+/// Warns about the invocations the lambda has not responded to when it asks for the next one.
+/// The runtime API expects one invocation at a time, so it is either a bug in the runtime or the handler
+/// or the lambda was restarted, e.g. after a crash. The invocations are removed from the registry to warn only once.
+pub(crate) fn abandon_outstanding() {
+    let abandoned = match invocations().lock() {
+        Ok(mut invocations) => invocations.drain().collect::<Vec<(String, Invocation)>>(),
+        Err(_) => return,
+    };
+
+    for (request_id, invocation) in abandoned {
+        let redelivery = if invocation.visible_until.is_some() {
+            "\nThe message stays invisible in the request queue until its visibility timeout expires and SQS re-delivers it."
+        } else {
+            ""
+        };
+        let _span = invocation.span.enter();
+        warn!(
+            "The lambda requested the next invocation without responding to {} served {}s ago.\nIt is a runtime API protocol violation unless the lambda was restarted.{redelivery}",
+            invocation.message_id.as_deref().unwrap_or(&request_id),
+            invocation.served_at.elapsed().as_secs()
+        );
+    }
+}
+
+/// Returns the span of the invocation the lambda responds to via `/runtime/invocation/[request-id]/response`
+/// or `/error` path or a disabled span if the invocation is unknown.
+pub(crate) fn span_for_path(path: &str) -> Span {
+    // receipt handles may contain `/`, so the request ID is everything between `/invocation/` and the last `/`
+    let request_id = path
+        .split_once("/invocation/")
+        .and_then(|(_, v)| v.rsplit_once('/'))
+        .map(|(v, _)| v);
+
+    request_id
+        .and_then(|v| invocations().lock().ok()?.get(v).map(|v| v.span.clone()))
+        .unwrap_or_else(Span::none)
+}
+
+/// Removes the invocation from the registry once the lambda responded to it and returns it, if it was recorded.
+/// Warns if the SQS message became visible in the queue while the lambda was processing it,
+/// e.g. while paused at a breakpoint, because SQS may have re-delivered it and may reject the response.
+/// Logs the REPORT line if LAMBDA_EMULATOR_REPORT_LINE is set.
+pub(crate) fn finish(request_id: &str) -> Option<Invocation> {
+    let invocation = invocations().lock().ok()?.remove(request_id)?;
+
+    let config = get_config();
+    if config.report_line {
+        report(request_id, &invocation, config.memory_size);
+    }
+
+    if invocation.visible_until.is_some_and(|v| Instant::now() > v) {
+        warn!(
+            "The lambda took {}s, which is longer than the visibility timeout of the request queue.\nThe message may have been re-delivered and SQS may reject its deletion. Expect a duplicate invocation.",
+            invocation.served_at.elapsed().as_secs()
+        );
+    }
+
+    Some(invocation)
+}
+
+/// Logs the duration of the invocation in the format of the REPORT line logged by AWS, e.g.
+/// `REPORT RequestId: local-request-id Duration: 12.34 ms Billed Duration: 13 ms Memory Size: 128 MB`.
+/// The duration is measured from the moment the event was served until the lambda responded,
+/// so it includes the time spent at breakpoints. The memory used by the lambda is not known to the emulator.
+/// The RequestId is the one the lambda got in the invocation headers. The SQS message ID is logged on its own line
+/// to match the REPORT line with the message in the queue.
+fn report(request_id: &str, invocation: &Invocation, memory_size: u32) {
+    let duration = invocation.served_at.elapsed();
+
+    // AWS bills by the millisecond, rounded up
+    let billed_ms = duration.as_micros().div_ceil(1000);
+
+    info!(
+        "REPORT RequestId: {request_id} Duration: {:.2} ms Billed Duration: {billed_ms} ms Memory Size: {memory_size} MB",
+        duration.as_secs_f64() * 1000.0
+    );
+    if let Some(message_id) = &invocation.message_id {
+        info!("REPORT SQS message ID: {message_id}");
+    }
+}
+
+/// Returns the key for counting the reruns of the invocation: the SQS message ID, if known, or the request ID.
+pub(crate) fn rerun_key(request_id: &str, invocation: Option<&Invocation>) -> String {
+    invocation
+        .and_then(|v| v.message_id.clone())
+        .unwrap_or_else(|| request_id.to_owned())
+}
+
+/// Counts a rerun of the failed invocation and returns its number if it is within `max_reruns`
+/// or None if the invocation ran out of reruns. The counter is reset once the limit is reached.
+pub(crate) fn try_rerun(key: &str, max_reruns: u32) -> Option<u32> {
+    let mut reruns = RERUNS.get_or_init(|| Mutex::new(HashMap::new())).lock().ok()?;
+
+    let count = reruns.entry(key.to_owned()).or_insert(0);
+    if *count < max_reruns {
+        *count += 1;
+        Some(*count)
+    } else {
+        reruns.remove(key);
+        None
+    }
+}
+
+/// Resets the rerun counter of the invocation once it succeeded.
+pub(crate) fn clear_reruns(key: &str) {
+    if let Some(Ok(mut reruns)) = RERUNS.get().map(|v| v.lock()) {
+        reruns.remove(key);
+    }
+}
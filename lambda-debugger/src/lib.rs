@@ -0,0 +1,148 @@
+//! AWS Lambda Runtime Emulator for local and remote debugging.
+//!
+//! The emulator can be embedded into tests:
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use lambda_debugger::{Config, Emulator};
+//!
+//! let emu = Emulator::start(Config::local("event.json")).await?;
+//! // point the lambda at the emulator with AWS_LAMBDA_RUNTIME_API env var
+//! println!("AWS_LAMBDA_RUNTIME_API={}", emu.addr());
+//! emu.shutdown().await
+//! # }
+//! ```
+
+use arc_swap::ArcSwap;
+use config::PayloadSources;
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use hyper::{Method, Request, Response};
+use std::sync::{Arc, OnceLock};
+use tracing::{debug, info, warn, Instrument};
+
+mod args;
+mod cloudevents;
+mod config;
+mod emulator;
+mod generators;
+mod handlers;
+mod invocations;
+mod memory;
+mod mutate;
+mod queues;
+mod runner;
+mod s3;
+mod schema;
+mod session;
+mod sqs;
+mod tls;
+mod transform;
+mod types;
+
+pub use config::Config;
+pub use emulator::Emulator;
+pub use runtime_emulator_types::*;
+pub use types::*;
+
+/// The current config. It is set when the emulator starts and can be replaced at runtime by sending SIGHUP to the emulator.
+static CONFIG: OnceLock<ArcSwap<Config>> = OnceLock::new();
+
+/// Returns a snapshot of the current config.
+/// The snapshot is not affected by config reloads, so it is safe to hold it across await points.
+pub(crate) fn get_config() -> Arc<Config> {
+    CONFIG
+        .get()
+        .expect("Config is used before it was initialized. It's a bug.")
+        .load_full()
+}
+
+/// Logs the request line and all headers as received from the lambda.
+/// Nothing is redacted because the requests come from the local lambda.
+fn trace_request(req: &Request<hyper::body::Incoming>) {
+    let headers = req
+        .headers()
+        .iter()
+        .map(|(name, value)| format!("{name}: {}", String::from_utf8_lossy(value.as_bytes())))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    info!("{} {} {:?}\n{headers}", req.method(), req.uri(), req.version());
+}
+
+/// The handler function converted into a Tower service to run in the background
+/// and serve the incoming HTTP requests from the local lambda.
+async fn lambda_api_handler(
+    req: Request<hyper::body::Incoming>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    debug!("Request URL: {:?}", req.uri());
+
+    if get_config().trace_http {
+        trace_request(&req);
+    }
+
+    if req.method() == Method::GET && req.uri().path().ends_with("/invocation/next") {
+        return Ok(handlers::next_invocation::handler().await);
+    }
+
+    if req.method() == Method::GET && req.uri().path().ends_with("/runtime/last-error") {
+        return Ok(handlers::last_error::handler().await);
+    }
+
+    if req.method() == Method::GET && req.uri().path().ends_with("/runtime/dropped") {
+        return Ok(handlers::dropped::handler(req).await);
+    }
+
+    // the in-memory queue endpoints for sending events and collecting responses
+    if matches!(
+        get_config().sources,
+        PayloadSources::Memory(_) | PayloadSources::Local(_)
+    ) {
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/local/enqueue") => return Ok(handlers::memory_queue::enqueue(req).await),
+            (&Method::POST, "/local/invoke") => return Ok(handlers::memory_queue::invoke(req).await),
+            (&Method::GET, "/local/dequeue") => return Ok(handlers::memory_queue::dequeue().await),
+            _ => {}
+        }
+    }
+
+    // a test hook for sending errors to the response queue without making the lambda fail
+    if req.method() == Method::POST && req.uri().path() == "/local/error" {
+        return Ok(handlers::local_error::handler(req).await);
+    }
+
+    if req.method() != Method::POST {
+        // There should be no other GET request types other than the above.
+        panic!("Invalid GET request: {:?}", req);
+    }
+
+    // the log lines of the response are grouped with the rest of the invocation
+    let span = invocations::span_for_path(req.uri().path());
+
+    if req.uri().path().ends_with("/response") {
+        let resp = handlers::lambda_response::handler(req).instrument(span).await;
+
+        // responses rejected with 400 are treated as lambda errors, e.g. in strict mode
+        // responses failed by LAMBDA_EMULATOR_ERROR_RATE are not final because the event is served again
+        if get_config().once {
+            if resp.status().is_success() {
+                emulator::finish_one_shot(Ok(()));
+            } else if resp.status() == hyper::StatusCode::BAD_REQUEST {
+                emulator::finish_one_shot(Err(HandlerError {
+                    error_type: Some("ResponseRejected".to_owned()),
+                    error_message: Some("The emulator rejected the lambda response".to_owned()),
+                    body: String::new(),
+                }));
+            }
+        }
+
+        return Ok(resp);
+    }
+
+    if req.uri().path().ends_with("/error") {
+        return Ok(handlers::lambda_error::handler(req).instrument(span).await);
+    }
+
+    // this should not be happening unless there is a bug or someone is sending requests manually
+    warn!("Unknown request type: {:?}", req);
+    Ok(handlers::lambda_error::handler(req).await)
+}
@@ -0,0 +1,227 @@
+use crate::config::{get_memory_config, PayloadSources};
+use crate::get_config;
+use crate::queues::{MessageSink, MessageSource};
+use crate::sqs::SqsMessage;
+use async_trait::async_trait;
+use lambda_runtime::Context as Ctx;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// An in-process replacement for the SQS request and response queues
+/// for debugging without any AWS dependencies.
+/// Both queues are bounded by the capacity set in MemoryConfig.
+struct MemoryQueue {
+    /// Request IDs and payloads waiting to be picked up by the lambda
+    sender: mpsc::Sender<(String, String)>,
+    /// Only one lambda at a time can wait for the next request
+    receiver: tokio::sync::Mutex<mpsc::Receiver<(String, String)>>,
+    /// Request IDs and lambda responses waiting to be collected
+    responses: Mutex<VecDeque<(String, String)>>,
+    /// IDs of the queued requests that have not been responded to yet,
+    /// with the channels to notify the clients waiting for the outcome via `/local/invoke?wait=true`
+    in_flight: Mutex<HashMap<String, Option<oneshot::Sender<InvocationResult>>>>,
+    /// Max number of messages in each queue
+    capacity: usize,
+}
+
+/// The outcome of a queued request for the client waiting for it.
+pub(crate) enum InvocationResult {
+    Response(String),
+    Error(String),
+}
+
+/// Request and response queues in memory.
+pub(crate) struct MemoryBackend;
+
+#[async_trait]
+impl MessageSource for MemoryBackend {
+    async fn next(&self) -> Result<SqsMessage, String> {
+        Ok(get_input().await)
+    }
+}
+
+#[async_trait]
+impl MessageSink for MemoryBackend {
+    async fn send(&self, body: String, handle: String) {
+        send_output(body, handle)
+    }
+}
+
+/// Initialized on first use with the capacity from the config.
+static MEMORY_QUEUE: OnceLock<MemoryQueue> = OnceLock::new();
+
+/// Returns the memory queue, creating it if needed.
+fn queue() -> &'static MemoryQueue {
+    MEMORY_QUEUE.get_or_init(|| {
+        // the queue is also used for pushing events in local mode
+        let capacity = match &get_config().sources {
+            PayloadSources::Memory(memory_config) => memory_config.capacity,
+            _ => get_memory_config().capacity,
+        };
+
+        let (sender, receiver) = mpsc::channel(capacity);
+
+        MemoryQueue {
+            sender,
+            receiver: tokio::sync::Mutex::new(receiver),
+            responses: Mutex::new(VecDeque::with_capacity(capacity)),
+            in_flight: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    })
+}
+
+/// Adds the payload to the request queue.
+/// Returns the request ID assigned to the payload or None if the queue is full.
+/// If `wait` is true, also returns a channel that receives the outcome of the invocation.
+pub(crate) fn enqueue(payload: String, wait: bool) -> Option<(String, Option<oneshot::Receiver<InvocationResult>>)> {
+    let queue = queue();
+    let request_id = uuid::Uuid::new_v4().to_string();
+
+    // the request has to be registered before the lambda can pick it up
+    let (notifier, receiver) = if wait {
+        let (notifier, receiver) = oneshot::channel();
+        (Some(notifier), Some(receiver))
+    } else {
+        (None, None)
+    };
+    if let Ok(mut in_flight) = queue.in_flight.lock() {
+        in_flight.insert(request_id.clone(), notifier);
+    }
+
+    match queue.sender.try_send((request_id.clone(), payload)) {
+        Ok(_) => {
+            info!("Request {request_id} queued");
+            Some((request_id, receiver))
+        }
+        Err(_) => {
+            warn!("Request rejected: the queue is full");
+            if let Ok(mut in_flight) = queue.in_flight.lock() {
+                in_flight.remove(&request_id);
+            }
+            None
+        }
+    }
+}
+
+/// Adds the payload to the request queue, waiting for a free slot if the queue is full.
+/// Returns the request ID assigned to the payload.
+pub(crate) async fn enqueue_when_ready(payload: String) -> String {
+    let queue = queue();
+    let request_id = uuid::Uuid::new_v4().to_string();
+
+    if let Ok(mut in_flight) = queue.in_flight.lock() {
+        in_flight.insert(request_id.clone(), None);
+    }
+
+    queue
+        .sender
+        .send((request_id.clone(), payload))
+        .await
+        .expect("Memory queue receiver was dropped. It's a bug.");
+    info!("Request {request_id} queued");
+
+    request_id
+}
+
+/// Returns true if the request ID belongs to a queued request that has not been responded to yet.
+pub(crate) fn is_in_flight(request_id: &str) -> bool {
+    match queue().in_flight.lock() {
+        Ok(in_flight) => in_flight.contains_key(request_id),
+        Err(_) => false,
+    }
+}
+
+/// Returns the next queued payload, if there is one, without waiting.
+/// It is used to serve the events pushed via `/local/invoke` ahead of the local payload file.
+pub(crate) fn try_get_input() -> Option<SqsMessage> {
+    let (request_id, payload) = queue().receiver.try_lock().ok()?.try_recv().ok()?;
+
+    Some(to_sqs_message(request_id, payload))
+}
+
+/// Waits for the next payload to arrive in the request queue and returns it in the same format as SQS messages.
+async fn get_input() -> SqsMessage {
+    let mut receiver = queue().receiver.lock().await;
+
+    info!("Lambda connected. Waiting for an incoming event via POST /local/enqueue or /local/invoke.");
+
+    let (request_id, payload) = receiver
+        .recv()
+        .await
+        .expect("Memory queue sender was dropped. It's a bug.");
+
+    to_sqs_message(request_id, payload)
+}
+
+/// Wraps the queued payload into the same format as SQS messages.
+fn to_sqs_message(request_id: String, payload: String) -> SqsMessage {
+    // the context is normally passed from AWS, so it has to be made up here
+    let mut ctx = Ctx::default();
+    ctx.request_id = request_id.clone();
+    ctx.deadline = 2035313041000; // 2034
+    ctx.invoked_function_arn = "from-memory-queue".to_owned();
+    ctx.env_config = Arc::new(lambda_runtime::Config {
+        memory: get_config().memory_size as i32,
+        ..Default::default()
+    });
+
+    SqsMessage {
+        payload,
+        receipt_handle: request_id,
+        message_id: None,
+        ctx,
+        visible_until: None,
+    }
+}
+
+/// Passes the lambda response to the client waiting for it or adds it to the response queue.
+/// The oldest response is dropped if the queue is full.
+fn send_output(response: String, request_id: String) {
+    let queue = queue();
+
+    let response = match take_notifier(&request_id) {
+        Some(notifier) => match notifier.send(InvocationResult::Response(response)) {
+            Ok(_) => {
+                info!("Response to {request_id} returned to the caller");
+                return;
+            }
+            // the caller is no longer waiting, so the response is queued instead
+            Err(InvocationResult::Response(response)) | Err(InvocationResult::Error(response)) => response,
+        },
+        None => response,
+    };
+
+    if let Ok(mut responses) = queue.responses.lock() {
+        if responses.len() >= queue.capacity {
+            if let Some((dropped_id, _)) = responses.pop_front() {
+                warn!("Response to {dropped_id} dropped: the response queue is full");
+            }
+        }
+        info!("Response to {request_id} queued");
+        responses.push_back((request_id, response));
+    }
+}
+
+/// Passes the lambda error to the client waiting for it.
+/// Errors are not added to the response queue.
+pub(crate) fn send_error(error: String, request_id: &str) {
+    if let Some(notifier) = take_notifier(request_id) {
+        if notifier.send(InvocationResult::Error(error)).is_ok() {
+            info!("Error in {request_id} returned to the caller");
+        }
+    }
+}
+
+/// Removes the request from the list of in-flight requests and returns
+/// the channel of the client waiting for its outcome, if any.
+fn take_notifier(request_id: &str) -> Option<oneshot::Sender<InvocationResult>> {
+    queue().in_flight.lock().ok()?.remove(request_id).flatten()
+}
+
+/// Removes the oldest response from the response queue and returns it with its request ID.
+pub(crate) fn dequeue() -> Option<(String, String)> {
+    queue().responses.lock().ok()?.pop_front()
+}
@@ -0,0 +1,179 @@
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// The number of payloads mutated so far. It is shared by all `counter` mutations.
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Generators of values for the mutated fields.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MutationKind {
+    /// 1, 2, 3... incremented with every invocation
+    Counter,
+    /// A random UUID v4
+    Uuid,
+    /// Milliseconds since the epoch
+    Timestamp,
+}
+
+/// A field of the local payload that gets a new value on every invocation.
+/// Set with `--mutate [path]=[counter|uuid|timestamp]` param.
+#[derive(Clone, Debug)]
+pub(crate) struct Mutation {
+    /// JSON pointer to the field, e.g. `/id`
+    pub pointer: String,
+    pub kind: MutationKind,
+}
+
+impl Mutation {
+    /// Parses `path=kind` param value, e.g. `event.id=counter` or `/detail/id=uuid`.
+    /// The path is either a JSON pointer or a dot-separated list of fields where
+    /// the leading `event.` refers to the payload itself.
+    /// Panics on invalid values.
+    pub fn parse(param: &str) -> Self {
+        let (path, kind) = match param.split_once('=') {
+            Some(v) => v,
+            None => panic!("Invalid --mutate value: {param}. Expected `path=kind`, e.g. `event.id=counter`."),
+        };
+
+        let kind = match kind {
+            "counter" => MutationKind::Counter,
+            "uuid" => MutationKind::Uuid,
+            "timestamp" => MutationKind::Timestamp,
+            v => panic!("Invalid --mutate generator: {v}. Must be `counter`, `uuid` or `timestamp`."),
+        };
+
+        let pointer = if path.starts_with('/') {
+            path.to_owned()
+        } else {
+            let path = path.strip_prefix("event.").unwrap_or(path);
+            path.split('.')
+                .map(|v| format!("/{}", v.replace('~', "~0").replace('/', "~1")))
+                .collect::<String>()
+        };
+
+        if pointer.len() < 2 {
+            panic!("Invalid --mutate value: {param}. The path must point at a field inside the payload.");
+        }
+
+        Self { pointer, kind }
+    }
+}
+
+/// Applies the mutations to the payload and returns the mutated payload.
+/// Payloads that are not valid JSON are returned as-is.
+pub(crate) fn apply(payload: &str, mutations: &[Mutation]) -> String {
+    if mutations.is_empty() {
+        return payload.to_owned();
+    }
+
+    let mut payload_json = match serde_json::from_str::<Value>(payload) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Payload not mutated: not a valid JSON. {e}");
+            return payload.to_owned();
+        }
+    };
+
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed) + 1;
+
+    for mutation in mutations {
+        let value = match mutation.kind {
+            MutationKind::Counter => Value::from(counter),
+            MutationKind::Uuid => Value::from(uuid::Uuid::new_v4().to_string()),
+            MutationKind::Timestamp => Value::from(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("System time is before the epoch. It's a bug.")
+                    .as_millis() as u64,
+            ),
+        };
+
+        debug!("Mutating {} = {value}", mutation.pointer);
+        if !set_pointer(&mut payload_json, &mutation.pointer, value) {
+            warn!("Cannot mutate {}: the parent field does not exist", mutation.pointer);
+        }
+    }
+
+    payload_json.to_string()
+}
+
+/// Sets the value at the JSON pointer, adding the last field if it does not exist.
+/// Returns false if the parent of the field does not exist or cannot have fields.
+fn set_pointer(json: &mut Value, pointer: &str, value: Value) -> bool {
+    let (parent, field) = match pointer.rsplit_once('/') {
+        Some(v) => v,
+        None => return false,
+    };
+    let field = field.replace("~1", "/").replace("~0", "~");
+
+    match json.pointer_mut(parent) {
+        Some(Value::Object(parent)) => {
+            parent.insert(field, value);
+            true
+        }
+        Some(Value::Array(parent)) => match field.parse::<usize>().ok().and_then(|idx| parent.get_mut(idx)) {
+            Some(v) => {
+                *v = value;
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn mutate(payload: &str, param: &str) -> Value {
+        serde_json::from_str(&apply(payload, &[Mutation::parse(param)])).unwrap()
+    }
+
+    #[test]
+    fn dotted_paths_become_pointers() {
+        assert_eq!(Mutation::parse("event.detail.id=uuid").pointer, "/detail/id");
+        assert_eq!(Mutation::parse("a/b.c~d=uuid").pointer, "/a~1b/c~0d");
+        assert_eq!(Mutation::parse("/detail/id=counter").pointer, "/detail/id");
+    }
+
+    #[test]
+    fn existing_and_new_fields_are_set() {
+        let payload = r#"{"id":"old","detail":{"name":"x"}}"#;
+
+        let mutated = mutate(payload, "event.id=uuid");
+        assert_ne!(mutated["id"], "old");
+        assert_eq!(mutated["id"].as_str().unwrap().len(), 36);
+
+        let mutated = mutate(payload, "detail.ts=timestamp");
+        assert!(mutated["detail"]["ts"].is_u64());
+        assert_eq!(mutated["detail"]["name"], "x");
+    }
+
+    #[test]
+    fn array_items_are_replaced_in_range_only() {
+        let mutated = mutate(r#"{"items":[1,2]}"#, "/items/1=counter");
+        assert!(mutated["items"][1].is_u64());
+        assert_eq!(mutated["items"][0], 1);
+
+        let mutated = mutate(r#"{"items":[1,2]}"#, "/items/5=counter");
+        assert_eq!(mutated, json!({"items":[1,2]}));
+    }
+
+    #[test]
+    fn missing_parent_leaves_payload_unchanged() {
+        let mutated = mutate(r#"{"a":1}"#, "missing.id=counter");
+
+        assert_eq!(mutated, json!({"a":1}));
+    }
+
+    #[test]
+    fn non_json_payload_is_passed_through() {
+        let payload = "not json {";
+
+        assert_eq!(apply(payload, &[Mutation::parse("id=counter")]), payload);
+    }
+}
@@ -0,0 +1,17 @@
+use crate::sqs::SqsMessage;
+use async_trait::async_trait;
+
+/// A source of invocation payloads for the lambda, e.g. the SQS request queue.
+#[async_trait]
+pub(crate) trait MessageSource: Send + Sync {
+    /// Waits for the next message to arrive and returns it.
+    /// Returns an error if no more messages can be received, e.g. the queue was deleted.
+    async fn next(&self) -> Result<SqsMessage, String>;
+}
+
+/// A destination for the lambda responses, e.g. the SQS response queue.
+#[async_trait]
+pub(crate) trait MessageSink: Send + Sync {
+    /// Sends the response back to the caller and acknowledges the request identified by the handle.
+    async fn send(&self, body: String, handle: String);
+}
@@ -0,0 +1,90 @@
+use crate::config::env_file_vars;
+use crate::get_config;
+use std::net::SocketAddr;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Starts the lambda with the shell command from `--run` param and the env vars it needs to connect to the emulator.
+/// The lambda's stdout and stderr are forwarded to the emulator log line by line.
+/// The lambda is killed when the returned task is aborted, together with all the processes started by the command.
+pub(crate) fn spawn(command: &str, addr: SocketAddr) -> JoinHandle<()> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+
+    // the vars from `--env-file` are not in the emulator's own env, so they are passed on explicitly
+    cmd.envs(env_file_vars())
+        .env("AWS_LAMBDA_RUNTIME_API", addr.to_string())
+        .env("AWS_LAMBDA_FUNCTION_VERSION", "$LATEST")
+        .env("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", get_config().memory_size.to_string())
+        .env("AWS_LAMBDA_FUNCTION_NAME", "my-lambda")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    // the shell and everything it starts, e.g. cargo and the lambda binary, get their own process group
+    // to be killed together because killing the shell leaves its children running
+    #[cfg(unix)]
+    cmd.process_group(0);
+
+    let command = command.to_owned();
+
+    tokio::spawn(async move {
+        let mut child = match cmd.spawn() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to start the lambda with `{command}`\n{:?}", e);
+                return;
+            }
+        };
+        info!("Started the lambda with `{command}`");
+
+        #[cfg(unix)]
+        let _process_group = child.id().map(|v| ProcessGroup(v as i32));
+
+        if let Some(stdout) = child.stdout.take() {
+            tokio::spawn(forward_lines(stdout));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(forward_lines(stderr));
+        }
+
+        match child.wait().await {
+            Ok(status) => warn!("The lambda exited with {status}"),
+            Err(e) => error!("Failed to wait for the lambda to exit\n{:?}", e),
+        }
+    })
+}
+
+/// Kills all processes in the group when dropped. The ID of the group is the PID of the shell that started it.
+#[cfg(unix)]
+struct ProcessGroup(i32);
+
+#[cfg(unix)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        // SAFETY: kill() has no memory safety requirements and the negative PID addresses the whole group
+        // the group may be gone already if the lambda exited on its own, which is not an error
+        unsafe {
+            libc::kill(-self.0, libc::SIGKILL);
+        }
+    }
+}
+
+/// Logs the output of the lambda line by line with a prefix to tell it apart from the emulator output.
+async fn forward_lines<R: AsyncRead + Unpin>(output: R) {
+    let mut lines = BufReader::new(output).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!("[lambda] {line}");
+    }
+}
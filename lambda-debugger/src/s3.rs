@@ -0,0 +1,77 @@
+#[cfg(feature = "s3")]
+use aws_sdk_s3::Client as S3Client;
+#[cfg(feature = "s3")]
+use tracing::info;
+
+/// Downloads the payload from an S3 object URL, e.g. `s3://my-bucket/events/test-payload.json`.
+/// Returns the raw contents of the object.
+/// Panics if the URL is invalid or the object cannot be downloaded because there is nothing to send to the lambda.
+pub(crate) async fn get_payload(url: &str) -> Vec<u8> {
+    let Some((bucket, key)) = parse_url(url) else {
+        panic!("Invalid S3 URL: {url}. Expected s3://bucket/key, e.g. s3://my-bucket/test-payload.json")
+    };
+
+    download(url, bucket, key).await
+}
+
+/// Splits `s3://bucket/key` into the bucket and the key.
+/// Returns None if either part is missing or the key points at a folder rather than an object.
+fn parse_url(url: &str) -> Option<(&str, &str)> {
+    match url.strip_prefix("s3://")?.split_once('/')? {
+        (bucket, key) if !bucket.is_empty() && !key.is_empty() && !key.ends_with('/') => Some((bucket, key)),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "s3")]
+async fn download(url: &str, bucket: &str, key: &str) -> Vec<u8> {
+    info!("Downloading the payload from {url}");
+    let client = S3Client::new(&aws_config::load_from_env().await);
+
+    let object = match client.get_object().bucket(bucket).key(key).send().await {
+        Ok(v) => v,
+        Err(e) => panic!("Failed to download the payload from {url}\n{:?}", e),
+    };
+
+    let body = match object.body.collect().await {
+        Ok(v) => v.into_bytes(),
+        Err(e) => panic!("Failed to read the payload from {url}\n{:?}", e),
+    };
+
+    body.to_vec()
+}
+
+#[cfg(not(feature = "s3"))]
+async fn download(url: &str, _bucket: &str, _key: &str) -> Vec<u8> {
+    panic!("Cannot download {url}: S3 payloads need the `s3` feature, e.g. cargo install lambda-debugger --features s3")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_split_into_bucket_and_key() {
+        assert_eq!(
+            parse_url("s3://my-bucket/test-payload.json"),
+            Some(("my-bucket", "test-payload.json"))
+        );
+        assert_eq!(
+            parse_url("s3://my-bucket/events/test-payload.json"),
+            Some(("my-bucket", "events/test-payload.json"))
+        );
+    }
+
+    #[test]
+    fn urls_without_a_key_are_rejected() {
+        assert_eq!(parse_url("s3://my-bucket"), None);
+        assert_eq!(parse_url("s3://my-bucket/"), None);
+        assert_eq!(parse_url("s3:///test-payload.json"), None);
+        assert_eq!(parse_url("my-bucket/test-payload.json"), None);
+    }
+
+    #[test]
+    fn urls_with_a_trailing_slash_are_rejected() {
+        assert_eq!(parse_url("s3://my-bucket/events/"), None);
+    }
+}
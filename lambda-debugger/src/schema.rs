@@ -0,0 +1,55 @@
+use crate::config::var;
+use jsonschema::Validator;
+use serde_json::Value;
+use tracing::{info, warn};
+
+/// Loads and compiles a JSON Schema from the file named in the env var, if the env var is set.
+/// Panics if the schema file cannot be read or is not a valid schema because
+/// there is no point running the emulator with a broken contract.
+pub(crate) fn load_schema(env_var: &str) -> Option<Validator> {
+    let file_name = var(env_var).ok().filter(|v| !v.trim().is_empty())?;
+
+    let schema = match std::fs::read_to_string(&file_name) {
+        Ok(v) => v,
+        Err(e) => panic!("Failed to read JSON schema from {file_name} ({env_var})\n{:?}", e),
+    };
+
+    let schema: Value = match serde_json::from_str(&schema) {
+        Ok(v) => v,
+        Err(e) => panic!("Invalid JSON in schema file {file_name} ({env_var})\n{:?}", e),
+    };
+
+    match jsonschema::validator_for(&schema) {
+        Ok(v) => {
+            info!("Validating against JSON schema from {file_name}");
+            Some(v)
+        }
+        Err(e) => panic!("Invalid JSON schema in {file_name} ({env_var})\n{e}"),
+    }
+}
+
+/// Validates the payload against the schema and logs all validation errors.
+/// The label is used in the log to tell what was validated, e.g. `Response`.
+/// Returns true if the payload is valid.
+pub(crate) fn validate(validator: &Validator, payload: &str, label: &str) -> bool {
+    let payload: Value = match serde_json::from_str(payload) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("{label} failed schema validation: not a valid JSON\n{:?}", e);
+            return false;
+        }
+    };
+
+    let errors = validator
+        .iter_errors(&payload)
+        .map(|e| format!("- {}: {e}", e.instance_path))
+        .collect::<Vec<String>>();
+
+    if errors.is_empty() {
+        return true;
+    }
+
+    warn!("{label} failed schema validation:\n{}", errors.join("\n"));
+
+    false
+}
@@ -0,0 +1,155 @@
+use crate::get_config;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Sends the lines to the writer thread with the name of the session file to append them to.
+/// A single writer keeps the lines in order and the file IO off the async handlers.
+static SESSION_WRITER: OnceLock<Sender<(String, String)>> = OnceLock::new();
+
+/// What the line of the session file holds.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+    /// An event served to the lambda
+    Event,
+    /// A response posted by the lambda
+    Response,
+    /// An error posted by the lambda
+    Error,
+}
+
+/// A line of the session file, e.g.
+/// `{"direction":"event","request_id":"local-request-id","timestamp":1700000000000,"payload":"{\"a\":1}"}`.
+/// The payload is kept as a string because it does not have to be JSON.
+#[derive(Serialize, Deserialize)]
+struct SessionEntry {
+    direction: Direction,
+    request_id: String,
+    /// Milliseconds since the epoch
+    timestamp: u64,
+    payload: String,
+}
+
+/// Appends the payload to the session file from LAMBDA_EMULATOR_SESSION_FILE env var, if it is set.
+/// Failures are logged and do not stop the invocation.
+pub(crate) fn record(direction: Direction, request_id: &str, payload: &str) {
+    let config = get_config();
+    let Some(session_file) = &config.session_file else {
+        return;
+    };
+
+    let entry = SessionEntry {
+        direction,
+        request_id: request_id.to_owned(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|v| v.as_millis() as u64)
+            .unwrap_or_default(),
+        payload: payload.to_owned(),
+    };
+
+    let mut line = serde_json::to_string(&entry).expect("Failed to serialize a session entry. It's a bug.");
+    line.push('\n');
+
+    let writer = SESSION_WRITER.get_or_init(|| {
+        let (sender, receiver) = channel::<(String, String)>();
+        std::thread::spawn(move || {
+            for (session_file, line) in receiver {
+                append(&session_file, &line);
+            }
+        });
+        sender
+    });
+
+    if writer.send((session_file.clone(), line)).is_err() {
+        warn!("Failed to record the {direction:?} in {session_file}: the session writer stopped");
+    }
+}
+
+/// Appends the line to the session file, creating it if needed. Failures are logged.
+fn append(session_file: &str, line: &str) {
+    if let Err(e) = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(session_file)
+        .and_then(|mut v| v.write_all(line.as_bytes()))
+    {
+        warn!("Failed to write to session file {session_file}: {:?}", e);
+    }
+}
+
+/// Reads the events from the session file in the order they were recorded, skipping the responses and errors.
+/// Panics if the file cannot be read, has invalid lines or has no events because there is nothing to replay.
+pub(crate) fn read_events(session_file: &str) -> Vec<String> {
+    let contents = match std::fs::read_to_string(session_file) {
+        Ok(v) => v,
+        Err(e) => panic!("Failed to read session file {session_file}\n{:?}", e),
+    };
+
+    let events = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(idx, line)| match serde_json::from_str::<SessionEntry>(line) {
+            Ok(v) => (v.direction == Direction::Event).then_some(v.payload),
+            Err(e) => panic!("Invalid session entry in {session_file} on line {}\n{e}", idx + 1),
+        })
+        .collect::<Vec<String>>();
+
+    if events.is_empty() {
+        panic!("No events to replay in {session_file}");
+    }
+
+    info!("Replaying {} events from {session_file}", events.len());
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(direction: Direction, payload: &str) -> String {
+        let entry = SessionEntry {
+            direction,
+            request_id: "local-request-id".to_owned(),
+            timestamp: 1700000000000,
+            payload: payload.to_owned(),
+        };
+        format!("{}\n", serde_json::to_string(&entry).unwrap())
+    }
+
+    fn session_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("lambda-debugger-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn recorded_events_are_replayed_in_order() {
+        let session_file = session_file("session-order.jsonl");
+        append(&session_file, &entry(Direction::Event, r#"{"a":1}"#));
+        append(&session_file, &entry(Direction::Response, r#"{"ok":true}"#));
+        append(&session_file, "\n");
+        append(&session_file, &entry(Direction::Event, r#"{"a":2}"#));
+        append(&session_file, &entry(Direction::Error, r#"{"errorType":"Oops"}"#));
+
+        assert_eq!(read_events(&session_file), vec![r#"{"a":1}"#, r#"{"a":2}"#]);
+        let _ = std::fs::remove_file(&session_file);
+    }
+
+    #[test]
+    #[should_panic(expected = "on line 2")]
+    fn invalid_session_entries_are_reported_with_line_number() {
+        let session_file = session_file("session-invalid.jsonl");
+        append(&session_file, &entry(Direction::Event, r#"{"a":1}"#));
+        append(&session_file, "{\"direction\":\"event\"\n");
+
+        read_events(&session_file);
+    }
+}
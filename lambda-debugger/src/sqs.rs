@@ -1,21 +1,62 @@
-use crate::CONFIG;
+use crate::codec::{self, Encoding};
+use crate::config::{parse_env_var, Config};
+use crate::get_config;
+use crate::queues::{MessageSink, MessageSource};
+use crate::{ErrorPayload, RequestPayload};
 use async_once::AsyncOnce;
-use aws_sdk_sqs::{types::Message, Client as SqsClient};
-use flate2::read::GzEncoder;
-use flate2::Compression;
+use async_trait::async_trait;
+use aws_sdk_sqs::config::http::HttpResponse;
+use aws_sdk_sqs::config::timeout::TimeoutConfig;
+use aws_sdk_sqs::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_sqs::types::{
+    DeleteMessageBatchRequestEntry, Message, MessageAttributeValue, MessageSystemAttributeName, QueueAttributeName,
+    SendMessageBatchRequestEntry,
+};
+use aws_sdk_sqs::Client as SqsClient;
 use lambda_runtime::Context as Ctx;
 use lazy_static::lazy_static;
-use runtime_emulator_types::RequestPayload;
-use std::io::prelude::*;
-use tokio::time::{sleep, Duration};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::OnceCell;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
 use tracing::{info, warn};
 
 // Cannot use OnceCell because it does not support async initialization
 lazy_static! {
-    pub(crate) static ref SQS_CLIENT: AsyncOnce<SqsClient> =
-        AsyncOnce::new(async { SqsClient::new(&aws_config::load_from_env().await) });
+    pub(crate) static ref SQS_CLIENT: AsyncOnce<SqsClient> = AsyncOnce::new(async {
+        let sdk_config = aws_config::load_from_env().await;
+        let mut config = aws_sdk_sqs::config::Builder::from(&sdk_config);
+
+        // the client is created before the config is initialized, so the timeout is read directly from the env var
+        if let Some(timeout) = parse_env_var::<u64>("LAMBDA_EMULATOR_SQS_TIMEOUT_SECS") {
+            // receive calls wait for up to 20s for messages to arrive
+            if timeout <= 20 {
+                panic!("Invalid value in LAMBDA_EMULATOR_SQS_TIMEOUT_SECS env var: {timeout}. Must be more than 20s, e.g. 30");
+            }
+            config = config.timeout_config(
+                TimeoutConfig::builder()
+                    .operation_attempt_timeout(Duration::from_secs(timeout))
+                    .build(),
+            );
+        }
+
+        SqsClient::from_conf(config.build())
+    });
 }
 
+/// The visibility timeout of the request queue, if known. It is fetched from SQS once.
+static VISIBILITY_TIMEOUT: OnceCell<Option<Duration>> = OnceCell::const_new();
+
+/// How long FIFO deduplication IDs are remembered. It is the same as the SQS deduplication interval.
+const DEDUP_WINDOW: Duration = Duration::from_secs(300);
+
+/// Deduplication IDs of the recently served FIFO messages with their message IDs and when they were served,
+/// oldest first.
+static SEEN_DEDUP_IDS: Mutex<VecDeque<(String, Option<String>, Instant)>> = Mutex::new(VecDeque::new());
+
 /// A parsed SQS message.
 /// The parsing is limited to extracting the data we need and passing the rest to the runtime.
 #[derive(Debug)]
@@ -23,19 +64,51 @@ pub(crate) struct SqsMessage {
     pub payload: String,
     /// the message receipt is needed to delete the message from the queue later
     pub receipt_handle: String,
+    /// The ID of the message as shown in the AWS console, unlike the receipt handle.
+    /// None for messages that did not come from SQS.
+    pub message_id: Option<String>,
     /// From the context
     pub ctx: Ctx,
+    /// When the message becomes visible in the queue again unless it is deleted by then.
+    /// None for messages that did not come from SQS.
+    pub visible_until: Option<Instant>,
+}
+
+/// Request and response queues in SQS.
+pub(crate) struct SqsBackend;
+
+#[async_trait]
+impl MessageSource for SqsBackend {
+    async fn next(&self) -> Result<SqsMessage, String> {
+        get_input().await
+    }
 }
 
-/// Reads a message from the specified SQS queue and returns the payload as Lambda structures
-pub(crate) async fn get_input() -> SqsMessage {
-    let config = CONFIG.get().await;
+#[async_trait]
+impl MessageSink for SqsBackend {
+    async fn send(&self, body: String, handle: String) {
+        send_output(body, handle).await
+    }
+}
+
+/// Reads a message from the specified SQS queue and returns the payload as Lambda structures.
+/// Returns an error if the queue does not exist or the receive retries from the config ran out.
+pub(crate) async fn get_input() -> Result<SqsMessage, String> {
+    let config = get_config();
     let client = SQS_CLIENT.get().await;
 
     // time to wait for the next message in seconds
     // set to 0 to begin with a friendly message logic
     let mut wait_time = 0;
 
+    // the number of consecutive failed attempts to calculate the backoff
+    let mut failed_attempts = 0;
+
+    // counters for the periodic heartbeat message
+    let mut poll_count = 0u64;
+    let started_at = Instant::now();
+    let mut last_heartbeat = started_at;
+
     // start listening to the response
     loop {
         // try to get the next message and wait for it to arrive if none is ready
@@ -45,39 +118,79 @@ pub(crate) async fn get_input() -> SqsMessage {
             .max_number_of_messages(1)
             .set_queue_url(Some(config.remote_config().request_queue_url.clone()))
             .set_wait_time_seconds(Some(wait_time))
+            .message_system_attribute_names(MessageSystemAttributeName::MessageDeduplicationId)
             .send()
             .await
         {
-            Ok(v) => v,
+            Ok(v) => {
+                failed_attempts = 0;
+                v
+            }
             Err(e) => {
-                warn!("Failed to get messages: {}", e);
-                sleep(Duration::from_millis(5000)).await;
+                // retrying won't help if the queue was deleted or the URL is wrong
+                if e.as_service_error().is_some_and(|v| v.is_queue_does_not_exist()) {
+                    return Err(format!(
+                        "Request queue does not exist: {}\nCheck PROXY_LAMBDA_REQ_QUEUE_URL env var or create the queue.",
+                        config.remote_config().request_queue_url
+                    ));
+                }
+
+                // give up in scripted runs for the failure to be detectable
+                if config.max_receive_retries.is_some_and(|v| failed_attempts >= v) {
+                    return Err(format!(
+                        "Failed to get messages {} times in a row. Giving up as per LAMBDA_EMULATOR_MAX_RECEIVE_RETRIES: {e}",
+                        failed_attempts + 1
+                    ));
+                }
+
+                let delay = retry_delay(&e, failed_attempts);
+                failed_attempts += 1;
+                warn!("Failed to get messages, retrying in {}ms: {}", delay.as_millis(), e);
+                sleep(delay).await;
                 continue;
             }
         };
 
         // wait until a message arrives or the function is killed by AWS
         if resp.messages.is_none() {
+            poll_count += 1;
+
             // print a friendly reminder to send an event
             if wait_time == 0 {
                 info!("Lambda connected. Waiting for an incoming event from AWS.");
                 wait_time = 20;
             }
 
+            // let the user know the emulator is still alive during long waits
+            if let Some(interval) = config.heartbeat_interval {
+                if last_heartbeat.elapsed() >= interval {
+                    info!(
+                        "Still waiting for an incoming event from AWS: polled {poll_count} times in {}s",
+                        started_at.elapsed().as_secs()
+                    );
+                    last_heartbeat = Instant::now();
+                }
+            }
+
             continue;
         }
 
+        // the visibility timeout starts when the message is received
+        let received_at = Instant::now();
+
         // SQS returns an empty list returns when the queue wait time expires
         let mut msgs = resp.messages.expect("Failed to get list of messages");
 
         // extract the payload and the receipt handle
-        let (payload, receipt_handle) = if let Some(msg) = msgs.pop() {
+        let (payload, receipt_handle, message_id, attributes) = if let Some(msg) = msgs.pop() {
             match msg {
                 Message {
                     body: Some(body),
                     receipt_handle: Some(receipt_handle),
+                    message_id,
+                    attributes,
                     ..
-                } => (body, receipt_handle),
+                } => (body, receipt_handle, message_id, attributes),
                 _ => panic!("Invalid SQS message. Missing body or receipt: {:?}", msg),
             }
         } else {
@@ -85,6 +198,24 @@ pub(crate) async fn get_input() -> SqsMessage {
             continue;
         };
 
+        // only FIFO queues have deduplication IDs
+        let dedup_id = attributes.and_then(|mut v| v.remove(&MessageSystemAttributeName::MessageDeduplicationId));
+        if let Some(dedup_id) = dedup_id {
+            let is_duplicate = match SEEN_DEDUP_IDS.lock() {
+                Ok(mut v) => is_duplicate(&mut v, &dedup_id, message_id.as_deref(), Instant::now()),
+                Err(e) => panic!("SEEN_DEDUP_IDS mutex is poisoned. It's a bug. {:?}", e),
+            };
+            // the message is left in the queue for SQS to expire or move to the DLQ
+            if is_duplicate {
+                info!(
+                    "Skipped a duplicate message {}: deduplication ID {dedup_id} was served in the last {}s",
+                    message_id.as_deref().unwrap_or_default(),
+                    DEDUP_WINDOW.as_secs()
+                );
+                continue;
+            }
+        }
+
         // the SQS payload contains event and context that need to be extracted
         // there is no way to pass the context to the lambda, but we can at least log it
         // the payload that is passed to the lambda is in event property
@@ -110,25 +241,123 @@ pub(crate) async fn get_input() -> SqsMessage {
         //       },
         //   }
 
-        let payload: RequestPayload = serde_json::from_str(&payload).expect("Failed to deserialize msg body");
-        let ctx = payload.ctx;
+        let request: RequestPayload = serde_json::from_str(&payload).expect("Failed to deserialize msg body");
+        let ctx = request.ctx;
 
-        let payload = serde_json::to_string(&payload.event).expect("event contents cannot be serialized");
+        // the lambda gets the whole { event, ctx } envelope in `--pass-full-payload` mode
+        let payload = if config.pass_full_payload {
+            payload
+        } else {
+            serde_json::to_string(&request.event).expect("event contents cannot be serialized")
+        };
 
         // if we reached this point, we have a parsed SQS message
         // with the payload and the receipt handle
         // and should return it to the caller
-        return SqsMessage {
+        let visible_until = visibility_timeout(client, &config.remote_config().request_queue_url)
+            .await
+            .map(|v| received_at + v);
+
+        return Ok(SqsMessage {
             payload,
             receipt_handle,
+            message_id,
             ctx,
-        };
+            visible_until,
+        });
+    }
+}
+
+/// Returns true if a different message with the same deduplication ID was served within DEDUP_WINDOW before `now`.
+/// Redeliveries of the same message, e.g. for a rerun, are not duplicates.
+/// `seen` is updated with the new deduplication IDs and cleared of the expired ones.
+fn is_duplicate(
+    seen: &mut VecDeque<(String, Option<String>, Instant)>,
+    dedup_id: &str,
+    message_id: Option<&str>,
+    now: Instant,
+) -> bool {
+    while seen
+        .front()
+        .is_some_and(|(_, _, served_at)| now.duration_since(*served_at) > DEDUP_WINDOW)
+    {
+        seen.pop_front();
+    }
+
+    match seen.iter().find(|(id, _, _)| id == dedup_id) {
+        Some((_, seen_message_id, _)) => seen_message_id.as_deref() != message_id,
+        None => {
+            seen.push_back((dedup_id.to_owned(), message_id.map(|v| v.to_owned()), now));
+            false
+        }
+    }
+}
+
+/// Returns the visibility timeout of the request queue to detect responses that took too long.
+/// It is fetched from SQS on the first call. Returns None if it cannot be fetched.
+async fn visibility_timeout(client: &SqsClient, request_queue_url: &str) -> Option<Duration> {
+    *VISIBILITY_TIMEOUT
+        .get_or_init(|| async {
+            match client
+                .get_queue_attributes()
+                .set_queue_url(Some(request_queue_url.to_owned()))
+                .attribute_names(QueueAttributeName::VisibilityTimeout)
+                .send()
+                .await
+            {
+                Ok(v) => v
+                    .attributes
+                    .and_then(|v| v.get(&QueueAttributeName::VisibilityTimeout)?.parse::<u64>().ok())
+                    .map(Duration::from_secs),
+                Err(e) => {
+                    warn!("Failed to get the visibility timeout of the request queue: {}", e);
+                    None
+                }
+            }
+        })
+        .await
+}
+
+/// Returns how long to wait before retrying a failed SQS call.
+/// Uses the delay from `Retry-After` header if SQS sent one.
+/// Otherwise, backs off exponentially starting at 1s for throttling and transient errors
+/// and at 5s for all other errors, up to 30s.
+fn retry_delay<E: ProvideErrorMetadata>(e: &SdkError<E, HttpResponse>, failed_attempts: u32) -> Duration {
+    if let Some(retry_after) = e
+        .raw_response()
+        .and_then(|v| v.headers().get("retry-after"))
+        .and_then(|v| v.trim().parse::<u64>().ok())
+    {
+        return Duration::from_secs(retry_after);
+    }
+
+    // SQS uses different codes for throttling, e.g. ThrottlingException or RequestThrottled
+    let is_throttling = e.code().is_some_and(|v| v.contains("Throttl"));
+    let is_transient = matches!(e, SdkError::TimeoutError(_) | SdkError::DispatchFailure(_));
+
+    let base_delay = if is_throttling || is_transient { 1000 } else { 5000 };
+
+    Duration::from_millis((base_delay << failed_attempts.min(5)).min(30000))
+}
+
+/// Returns true if a failed SQS call may succeed on a retry: throttling, timeouts, network and server errors.
+/// Other service errors, e.g. a missing queue or permission, fail the same way every time.
+fn is_retryable<E: ProvideErrorMetadata>(e: &SdkError<E, HttpResponse>) -> bool {
+    match e {
+        SdkError::ServiceError(_) => {
+            e.code().is_some_and(|v| v.contains("Throttl"))
+                || e.raw_response().is_some_and(|v| v.status().is_server_error())
+        }
+        _ => true,
     }
 }
 
 /// Returns URLs of the default request and response queues, if they exist.
+/// The queue names are `[prefix]req` and `[prefix]resp` with the prefix from LAMBDA_EMULATOR_QUEUE_PREFIX env var
+/// or `proxy_lambda_` by default.
 pub(crate) async fn get_default_queues() -> (Option<String>, Option<String>) {
     let client = SQS_CLIENT.get().await;
+    let prefix = queue_prefix();
 
     // example of the default request queue URL
     // https://sqs.us-east-1.amazonaws.com/512295225992/proxy_lambda_req
@@ -136,7 +365,7 @@ pub(crate) async fn get_default_queues() -> (Option<String>, Option<String>) {
     // get the list of queues that start with the default queue prefix
     let resp = match client
         .list_queues()
-        .set_queue_name_prefix(Some("proxy_lambda_re".to_string()))
+        .set_queue_name_prefix(Some(format!("{prefix}re")))
         .set_max_results(Some(100))
         .send()
         .await
@@ -147,88 +376,780 @@ pub(crate) async fn get_default_queues() -> (Option<String>, Option<String>) {
         }
     };
 
-    // output containers
-    let mut req_queue = None;
-    let mut resp_queue = None;
+    let queue_urls = resp.queue_urls.unwrap_or_default();
+
+    (
+        match_queue(&queue_urls, &format!("{prefix}req")),
+        match_queue(&queue_urls, &format!("{prefix}resp")),
+    )
+}
+
+/// Returns the URL of the queue with the exact name or the lexicographically first queue which name starts with it,
+/// e.g. `proxy_lambda_req_v2` for `proxy_lambda_req`. Logs the candidates if there is no exact match.
+fn match_queue(queue_urls: &[String], name: &str) -> Option<String> {
+    // the queue name is the last part of the URL, e.g. https://sqs.us-east-1.amazonaws.com/512295225992/proxy_lambda_req
+    let queue_name = |url: &String| url.rsplit('/').next().unwrap_or_default().to_owned();
+
+    if let Some(url) = queue_urls.iter().find(|v| queue_name(v) == name) {
+        return Some(url.clone());
+    }
+
+    let mut candidates = queue_urls
+        .iter()
+        .filter(|v| queue_name(v).starts_with(name))
+        .collect::<Vec<&String>>();
+    candidates.sort();
+
+    let url = candidates.first()?;
+    warn!(
+        "No {name} queue found. Using {url} out of:\n{}\nSet the queue URL in the env var to use a different one.",
+        candidates.iter().map(|v| v.as_str()).collect::<Vec<&str>>().join("\n")
+    );
+
+    Some(url.to_string())
+}
+
+/// Returns the prefix of the default queue names from LAMBDA_EMULATOR_QUEUE_PREFIX env var or `proxy_lambda_`.
+fn queue_prefix() -> String {
+    crate::config::var("LAMBDA_EMULATOR_QUEUE_PREFIX")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| "proxy_lambda_".to_string())
+}
 
-    // match queue names against the default names
-    if let Some(queue_urls) = resp.queue_urls {
-        for url in queue_urls {
-            if url.ends_with("/proxy_lambda_req") {
-                req_queue = Some(url);
-            } else if url.ends_with("/proxy_lambda_resp") {
-                resp_queue = Some(url);
+/// Prints the default queues found in SQS in response to `list-queues` param
+/// to let the user check their AWS setup without starting the emulator.
+pub(crate) async fn list_queues() {
+    let prefix = queue_prefix();
+    println!("Looking for {prefix}req and {prefix}resp queues");
+
+    match get_default_queues().await {
+        (None, None) => {
+            println!("No queues found.");
+            println!("Create {prefix}req and {prefix}resp queues in the region of your AWS profile or set");
+            println!("PROXY_LAMBDA_REQ_QUEUE_URL and LAMBDA_PROXY_RESP_QUEUE_URL env vars with the queue URLs.");
+        }
+        (req_queue, resp_queue) => {
+            println!("- request queue:  {}", req_queue.as_deref().unwrap_or("not found"));
+            println!("- response queue: {}", resp_queue.as_deref().unwrap_or("not found"));
+            if req_queue.is_none() {
+                println!("The request queue is required for remote debugging.");
             }
         }
     }
+}
+
+/// A receipt handle that does not belong to any message. It is used to probe the permissions without side effects.
+const PROBE_RECEIPT_HANDLE: &str = "lambda-debugger-permission-probe";
+
+/// Probes every SQS action the emulator needs in response to `--check-permissions` param and prints a checklist
+/// to let the user find missing permissions without starting the emulator.
+/// The probes are harmless: the send and delete calls are made invalid on purpose,
+/// so SQS rejects them after checking the permissions.
+/// sqs:ReceiveMessage is only probed with `probe_receive` because a real receive counts towards the DLQ redrive limit.
+pub(crate) async fn check_permissions(probe_receive: bool) {
+    let client = SQS_CLIENT.get().await;
+    let prefix = queue_prefix();
+    println!("Checking SQS permissions of the current AWS profile");
+
+    let list_result = client
+        .list_queues()
+        .set_queue_name_prefix(Some(prefix.clone()))
+        .set_max_results(Some(1))
+        .send()
+        .await;
+    let can_list = print_permission("sqs:ListQueues", &prefix, list_result);
+
+    // the queue URLs from the env vars have priority over the defaults, same as in the emulator
+    let (default_req_queue, default_resp_queue) = if can_list {
+        get_default_queues().await
+    } else {
+        (None, None)
+    };
+    let request_queue_url = crate::config::var("PROXY_LAMBDA_REQ_QUEUE_URL")
+        .ok()
+        .or(default_req_queue);
+    let response_queue_url = crate::config::var("LAMBDA_PROXY_RESP_QUEUE_URL")
+        .ok()
+        .or(default_resp_queue);
 
-    (req_queue, resp_queue)
+    match &request_queue_url {
+        Some(url) => {
+            if probe_receive {
+                warn!("Probing sqs:ReceiveMessage may receive a message and move it closer to the DLQ");
+                let result = client
+                    .receive_message()
+                    .set_queue_url(Some(url.clone()))
+                    .max_number_of_messages(1)
+                    .set_wait_time_seconds(Some(0))
+                    .visibility_timeout(0)
+                    .send()
+                    .await;
+                print_permission("sqs:ReceiveMessage", url, result);
+            } else {
+                println!("[?] sqs:ReceiveMessage was not checked: add --probe-receive to check it with a real receive");
+            }
+
+            let result = client
+                .delete_message()
+                .set_queue_url(Some(url.clone()))
+                .set_receipt_handle(Some(PROBE_RECEIPT_HANDLE.to_owned()))
+                .send()
+                .await;
+            print_permission("sqs:DeleteMessage", url, result);
+
+            let result = client
+                .change_message_visibility()
+                .set_queue_url(Some(url.clone()))
+                .set_receipt_handle(Some(PROBE_RECEIPT_HANDLE.to_owned()))
+                .visibility_timeout(0)
+                .send()
+                .await;
+            print_permission("sqs:ChangeMessageVisibility", url, result);
+
+            let result = client
+                .get_queue_attributes()
+                .set_queue_url(Some(url.clone()))
+                .attribute_names(QueueAttributeName::VisibilityTimeout)
+                .send()
+                .await;
+            print_permission("sqs:GetQueueAttributes", url, result);
+        }
+        None => println!("[?] no request queue: set PROXY_LAMBDA_REQ_QUEUE_URL or create {prefix}req"),
+    }
+
+    match &response_queue_url {
+        Some(url) => {
+            // the delay is over the 15 min limit, so the message is rejected and never reaches the queue
+            let result = client
+                .send_message()
+                .set_queue_url(Some(url.clone()))
+                .set_message_body(Some("{}".to_owned()))
+                .delay_seconds(901)
+                .send()
+                .await;
+            print_permission("sqs:SendMessage", url, result);
+        }
+        None => println!("[?] no response queue: sqs:SendMessage was not checked"),
+    }
+}
+
+/// Prints the outcome of a permission probe and returns true if the action is allowed.
+/// Any error other than access denied means that SQS checked the permissions and rejected the probe for other reasons.
+fn print_permission<T, E: ProvideErrorMetadata>(
+    action: &str,
+    resource: &str,
+    result: Result<T, SdkError<E, HttpResponse>>,
+) -> bool {
+    match result {
+        Ok(_) => {
+            println!("[x] {action} on {resource}");
+            true
+        }
+        Err(e @ SdkError::ServiceError(_)) => {
+            let code = e.code().unwrap_or_default();
+            if code.contains("AccessDenied") || code.contains("NotAuthorized") || code.contains("AuthorizationError") {
+                println!("[ ] {action} on {resource}: missing");
+                false
+            } else {
+                println!("[x] {action} on {resource}");
+                true
+            }
+        }
+        Err(e) => {
+            println!("[?] {action} on {resource}: could not check\n    {e}");
+            false
+        }
+    }
+}
+
+/// Makes the request message visible in the queue straight away for a rerun
+/// instead of waiting for its visibility timeout to expire.
+pub(crate) async fn release_message(receipt_handle: &str) {
+    let config = get_config();
+    let client = SQS_CLIENT.get().await;
+
+    if let Err(e) = client
+        .change_message_visibility()
+        .set_queue_url(Some(config.remote_config().request_queue_url.clone()))
+        .set_receipt_handle(Some(receipt_handle.to_owned()))
+        .visibility_timeout(0)
+        .send()
+        .await
+    {
+        warn!(
+            "Failed to release the message for a rerun. It will be re-delivered after its visibility timeout: {}",
+            e
+        );
+    }
+}
+
+/// Responses that failed to send with the receipt handles of their requests to delete once they are sent.
+/// The handle is None if the request is not deleted, e.g. for errors.
+/// The buffer is drained in the background by `drain_buffered_responses`.
+static BUFFERED_RESPONSES: Mutex<VecDeque<(String, Option<String>)>> = Mutex::new(VecDeque::new());
+
+/// Is set to TRUE while the buffered responses are being retried.
+static IS_DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// The task retrying the buffered responses, if one is running.
+static DRAIN_TASK: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Why a response could not be sent to the response queue.
+#[derive(Debug, PartialEq)]
+enum SendFailure {
+    /// Throttling, timeouts, network and server errors that may go away on a retry
+    Transient,
+    /// Errors that fail the same way every time, e.g. a deleted queue or a missing sqs:SendMessage permission
+    Permanent,
 }
 
+/// Max wait between the retries of the buffered responses
+const MAX_DRAIN_BACKOFF: Duration = Duration::from_secs(30);
+
 /// Send back the response and delete the message from the queue.
 pub(crate) async fn send_output(response: String, receipt_handle: String) {
-    let config = CONFIG.get().await;
+    let config = get_config();
+
+    delay_response(&config).await;
+
+    // the request is only deleted after the response was sent, so that it is not lost
+    // SQS re-delivers it after its visibility timeout for another try if it cannot be buffered
+    let Err((response, failure)) = try_send_to_response_queue(response).await else {
+        delete_request(&receipt_handle).await;
+        info!("Response sent and request deleted from the queue");
+        return;
+    };
+
+    if !buffer_response(response, Some(receipt_handle), failure) {
+        warn!("The request was left in the queue and will be re-delivered after its visibility timeout");
+    }
+}
+
+/// Deletes the request message from the queue so it cannot be replayed again.
+/// The message is re-delivered after its visibility timeout if it cannot be deleted.
+async fn delete_request(receipt_handle: &str) {
+    let config = get_config();
+    let client = SQS_CLIENT.get().await;
+
+    if let Err(e) = client
+        .delete_message()
+        .set_queue_url(Some(config.remote_config().request_queue_url.to_string()))
+        .set_receipt_handle(Some(receipt_handle.to_owned()))
+        .send()
+        .await
+    {
+        warn!(
+            "Failed to delete the request from the queue. It will be re-delivered after its visibility timeout: {}",
+            e
+        );
+    };
+}
+
+/// Sends the response to the response queue and returns it back with the reason if the send failed.
+async fn try_send_to_response_queue(response: String) -> Result<(), (String, SendFailure)> {
+    match send_to_response_queue(&response).await {
+        Ok(_) => Ok(()),
+        Err(failure) => Err((response, failure)),
+    }
+}
+
+/// Adds the response that failed to send to the buffer and starts retrying it in the background.
+/// Returns false if the buffer is disabled or full or if retrying would not help.
+fn buffer_response(response: String, receipt_handle: Option<String>, failure: SendFailure) -> bool {
+    if failure == SendFailure::Permanent {
+        warn!("The response is not buffered because retrying would not help");
+        return false;
+    }
+
+    let capacity = get_config().response_buffer_size;
+
+    match BUFFERED_RESPONSES.lock() {
+        Ok(mut buffer) if buffer.len() < capacity => {
+            buffer.push_back((response, receipt_handle));
+            warn!(
+                "Response buffered for a retry. Buffer depth: {} of {capacity}",
+                buffer.len()
+            );
+        }
+        Ok(_) => {
+            warn!("Response buffer is full ({capacity}). The response is not buffered.");
+            return false;
+        }
+        Err(_) => return false,
+    }
+
+    if !IS_DRAINING.swap(true, Ordering::SeqCst) {
+        let task = tokio::spawn(drain_buffered_responses());
+        if let Ok(mut drain_task) = DRAIN_TASK.lock() {
+            *drain_task = Some(task);
+        }
+    }
+
+    true
+}
+
+/// Stops retrying the buffered responses and drops them, e.g. when the emulator starts or shuts down.
+/// Their requests stay in the request queue for SQS to re-deliver them after their visibility timeout.
+pub(crate) fn clear_response_buffer() {
+    if let Some(task) = DRAIN_TASK.lock().ok().and_then(|mut v| v.take()) {
+        task.abort();
+    }
+
+    if let Ok(mut buffer) = BUFFERED_RESPONSES.lock() {
+        if !buffer.is_empty() {
+            warn!("{} buffered responses were not sent", buffer.len());
+            buffer.clear();
+        }
+        IS_DRAINING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Retries the buffered responses in the order they were buffered with an exponential backoff
+/// until the buffer is empty or the response queue fails with an error that retrying would not fix.
+/// The responses are sent in batches, so that a backlog built up during an outage is cleared with fewer requests.
+async fn drain_buffered_responses() {
+    let mut attempt = 0;
+
+    loop {
+        // the flag is reset under the lock, so that a response buffered after this check starts a new drain
+        match BUFFERED_RESPONSES.lock() {
+            Ok(buffer) if !buffer.is_empty() => {}
+            _ => {
+                IS_DRAINING.store(false, Ordering::SeqCst);
+                return;
+            }
+        }
+
+        sleep(Duration::from_secs(1 << attempt.min(5)).min(MAX_DRAIN_BACKOFF)).await;
+
+        // the responses are taken out of the buffer for the send and the failed ones are put back
+        let max_body_len = max_body_size(attributes_size(&get_config().response_attributes));
+        let batch = match BUFFERED_RESPONSES.lock() {
+            Ok(mut buffer) => take_batch(&mut buffer, max_body_len),
+            Err(_) => continue,
+        };
+        if batch.is_empty() {
+            continue;
+        }
+
+        let failed = match send_batch_to_response_queue(batch).await {
+            Ok(v) => v,
+            Err((batch, SendFailure::Permanent)) => {
+                if let Ok(mut buffer) = BUFFERED_RESPONSES.lock() {
+                    warn!(
+                        "Stopped retrying {} buffered responses because retrying would not help. Their requests will be re-delivered by SQS.",
+                        buffer.len() + batch.len()
+                    );
+                    buffer.clear();
+                    IS_DRAINING.store(false, Ordering::SeqCst);
+                }
+                return;
+            }
+            Err((batch, SendFailure::Transient)) => batch,
+        };
+
+        attempt = if failed.is_empty() { 0 } else { attempt + 1 };
+
+        if let Ok(mut buffer) = BUFFERED_RESPONSES.lock() {
+            for response in failed.into_iter().rev() {
+                buffer.push_front(response);
+            }
+            info!("Buffered responses sent. Buffer depth: {}", buffer.len());
+        }
+    }
+}
+
+/// Max number of messages in a single SQS batch request
+const MAX_BATCH_ENTRIES: usize = 10;
+
+/// Takes the responses from the front of the buffer that fit into a single SQS batch request.
+/// The batch is limited to 10 messages and to the max message size in total.
+/// A response is counted at its full size, but no more than `max_body_len` because bigger ones are compressed.
+/// The first response is always taken, so that the buffer moves on even if it is too big for SQS.
+fn take_batch(buffer: &mut VecDeque<(String, Option<String>)>, max_body_len: usize) -> Vec<(String, Option<String>)> {
+    let mut batch = Vec::new();
+    let mut batch_len = 0;
+
+    while let Some((response, _)) = buffer.front() {
+        let len = response.len().min(max_body_len);
+        if batch.len() == MAX_BATCH_ENTRIES || (!batch.is_empty() && batch_len + len > max_body_len) {
+            break;
+        }
+        batch_len += len;
+        batch.extend(buffer.pop_front());
+    }
+
+    batch
+}
+
+/// Sends the responses to the response queue in a single batch request and deletes the requests of the sent ones.
+/// Returns the responses that failed with an error that may go away on a retry, or all of them with the reason
+/// if the whole batch failed. The requests of the responses that failed for good stay in the queue for a rerun.
+async fn send_batch_to_response_queue(
+    batch: Vec<(String, Option<String>)>,
+) -> Result<Vec<(String, Option<String>)>, (Vec<(String, Option<String>)>, SendFailure)> {
+    let config = get_config();
+    let client = SQS_CLIENT.get().await;
+
+    let Some(response_queue_url) = config.remote_config().response_queue_url.clone() else {
+        info!("{} responses dropped: no response queue configured", batch.len());
+        delete_requests(batch.into_iter().filter_map(|(_, v)| v).collect()).await;
+        return Ok(Vec::new());
+    };
+
+    let attributes_len = attributes_size(&config.response_attributes);
+    let max_body_len = max_body_size(attributes_len);
+
+    // the entries are identified by their position in the batch
+    let mut entries = Vec::with_capacity(batch.len());
+    for (idx, (response, _)) in batch.iter().enumerate() {
+        let output = compress_output(response, config.encoding, max_body_len);
+        if output.encoded_len >= max_body_len {
+            info!(
+                " Response dropped: message size {}B, max allowed by SQS is {}B after {}B of attributes",
+                output.encoded_len, max_body_len, attributes_len
+            );
+            record_dropped(output.encoded_len);
+            continue;
+        }
+        entries.push(
+            SendMessageBatchRequestEntry::builder()
+                .id(idx.to_string())
+                .message_body(output.body)
+                .set_message_attributes(message_attributes(&config))
+                .build()
+                .expect("Failed to build a batch entry. It's a bug."),
+        );
+    }
+
+    let mut failed_ids = HashMap::new();
+    if !entries.is_empty() {
+        match client
+            .send_message_batch()
+            .set_queue_url(Some(response_queue_url))
+            .set_entries(Some(entries))
+            .send()
+            .await
+        {
+            Ok(v) => {
+                for entry in v.failed {
+                    warn!(
+                        "Failed to send buffered response {}: {} {}",
+                        entry.id,
+                        entry.code,
+                        entry.message.unwrap_or_default()
+                    );
+                    failed_ids.insert(entry.id, entry.sender_fault);
+                }
+            }
+            Err(e) => {
+                warn!("Failed to send a batch of {} SQS responses: {}", batch.len(), e);
+                return Err((
+                    batch,
+                    match is_retryable(&e) {
+                        true => SendFailure::Transient,
+                        false => SendFailure::Permanent,
+                    },
+                ));
+            }
+        }
+    }
+
+    // the entries rejected because of their contents would fail the same way on a retry
+    // the responses dropped for their size count as sent, same as in `send_to_response_queue`
+    let mut retry = Vec::new();
+    let mut sent = Vec::new();
+    for (idx, (response, receipt_handle)) in batch.into_iter().enumerate() {
+        match failed_ids.get(&idx.to_string()) {
+            Some(false) => retry.push((response, receipt_handle)),
+            Some(true) => warn!("Buffered response {idx} dropped because retrying would not help"),
+            None => sent.extend(receipt_handle),
+        }
+    }
+    delete_requests(sent).await;
+
+    Ok(retry)
+}
+
+/// Deletes the request messages from the queue in batches of up to 10.
+/// The messages that could not be deleted are re-delivered after their visibility timeout.
+async fn delete_requests(receipt_handles: Vec<String>) {
+    let config = get_config();
+    let client = SQS_CLIENT.get().await;
+
+    for chunk in receipt_handles.chunks(MAX_BATCH_ENTRIES) {
+        let entries = chunk
+            .iter()
+            .enumerate()
+            .map(|(idx, receipt_handle)| {
+                DeleteMessageBatchRequestEntry::builder()
+                    .id(idx.to_string())
+                    .receipt_handle(receipt_handle)
+                    .build()
+                    .expect("Failed to build a batch entry. It's a bug.")
+            })
+            .collect::<Vec<DeleteMessageBatchRequestEntry>>();
+
+        match client
+            .delete_message_batch()
+            .set_queue_url(Some(config.remote_config().request_queue_url.to_string()))
+            .set_entries(Some(entries))
+            .send()
+            .await
+        {
+            Ok(v) if v.failed.is_empty() => {}
+            Ok(v) => warn!(
+                "Failed to delete {} requests from the queue. They will be re-delivered after their visibility timeout.",
+                v.failed.len()
+            ),
+            Err(e) => warn!(
+                "Failed to delete {} requests from the queue. They will be re-delivered after their visibility timeout: {}",
+                chunk.len(),
+                e
+            ),
+        }
+    }
+}
+
+/// Sends back the response, but leaves the request in the queue for SQS to re-deliver it after its visibility timeout,
+/// e.g. to test the idempotency of the lambda. The lambda asks for it with `x-emulator-keep-request` header.
+pub(crate) async fn send_output_keeping_request(response: String) {
+    delay_response(&get_config()).await;
+
+    match try_send_to_response_queue(response).await {
+        Ok(_) => info!("Response sent, the request was kept in the queue as requested by the lambda"),
+        Err((response, failure)) => {
+            buffer_response(response, None, failure);
+        }
+    }
+}
+
+/// Simulates a slow response path to test the timeouts of the proxy and its callers.
+async fn delay_response(config: &Config) {
+    if let Some(delay) = config.response_delay {
+        info!("Delaying the response by {}ms", delay.as_millis());
+        sleep(delay).await;
+    }
+}
+
+/// Max size of a single SQS message in bytes, including its attributes
+const MAX_MESSAGE_SIZE: usize = 262144;
+
+/// Bytes reserved for the encoding overhead SQS may add on top of the body and attributes,
+/// so that messages just under the limit are not rejected
+const SIZE_SAFETY_MARGIN: usize = 1024;
+
+/// Sends the lambda error to the response queue wrapped into ErrorPayload.
+/// The request message stays in the queue for a rerun.
+pub(crate) async fn send_error(error: String) {
+    // the error is usually JSON, but it is forwarded as a string if it is not
+    let lambda_error = serde_json::from_str(&error).unwrap_or(serde_json::Value::String(error));
+
+    let error = match serde_json::to_string(&ErrorPayload { lambda_error }) {
+        Ok(v) => v,
+        Err(e) => panic!("Failed to serialize the lambda error: {:?}", e),
+    };
+
+    match try_send_to_response_queue(error).await {
+        Ok(_) => info!("Error sent to the response queue"),
+        Err((error, failure)) => {
+            buffer_response(error, None, failure);
+        }
+    }
+}
+
+/// Sends the error to the response queue and deletes the request message because a rerun would be rejected the same way,
+/// e.g. for requests that fail the schema validation in strict mode.
+pub(crate) async fn reject_request(error: String, receipt_handle: &str) {
+    send_error(error).await;
+    delete_request(receipt_handle).await;
+}
+
+/// Sends the message to the response queue, if there is one.
+/// Oversized messages are compressed or dropped.
+/// Returns the reason if the message could not be sent, so that the request can be left in the queue for a retry.
+async fn send_to_response_queue(response: &str) -> Result<(), SendFailure> {
+    let config = get_config();
     let client = SQS_CLIENT.get().await;
 
     let response_queue_url = match &config.remote_config().response_queue_url {
         Some(v) => v.clone(),
         None => {
             info!("Response dropped: no response queue configured");
-            return;
+            return Ok(());
         }
     };
 
-    let response = compress_output(response);
+    let attributes_len = attributes_size(&config.response_attributes);
+    let max_body_len = max_body_size(attributes_len);
+    let output = compress_output(response, config.encoding, max_body_len);
+
+    if output.was_compressed {
+        info!(
+            "Compression ratio: {:.2} ({}B -> {}B)",
+            output.ratio(),
+            output.original_len,
+            output.encoded_len
+        );
+    }
 
-    // SQS messages must be shorter than 262144 bytes
-    if response.len() < 262144 {
+    // SQS messages must be shorter than 262144 bytes, including the attributes
+    if output.encoded_len < max_body_len {
+        // the SDK needs its own copy of the body, which is only made for the responses that are not compressed
         if let Err(e) = client
             .send_message()
-            .set_message_body(Some(response))
+            .set_message_body(Some(output.body.into_owned()))
             .set_queue_url(Some(response_queue_url))
+            .set_message_attributes(message_attributes(&config))
             .send()
             .await
         {
-            panic!("Failed to send SQS response: {}", e);
+            warn!("Failed to send SQS response: {}", e);
+            return Err(match is_retryable(&e) {
+                true => SendFailure::Transient,
+                false => SendFailure::Permanent,
+            });
         };
     } else {
         info!(
-            " Response dropped: message size {}B, max allowed by SQS is 262,144 bytes",
-            response.len()
+            " Response dropped: message size {}B, max allowed by SQS is {}B after {}B of attributes",
+            output.encoded_len, max_body_len, attributes_len
         );
+        record_dropped(output.encoded_len);
     }
 
-    // delete the request msg from the queue so it cannot be replayed again
-    if let Err(e) = client
-        .delete_message()
-        .set_queue_url(Some(config.remote_config().request_queue_url.to_string()))
-        .set_receipt_handle(Some(receipt_handle))
-        .send()
-        .await
-    {
-        panic!("Failed to send SQS response: {}", e);
+    Ok(())
+}
+
+/// Returns the message attributes for the response queue from LAMBDA_EMULATOR_RESP_ATTRS or None if there are none.
+fn message_attributes(config: &Config) -> Option<HashMap<String, MessageAttributeValue>> {
+    if config.response_attributes.is_empty() {
+        return None;
+    }
+
+    let attributes = config
+        .response_attributes
+        .iter()
+        .map(|(key, value)| {
+            let value = MessageAttributeValue::builder()
+                .data_type("String")
+                .string_value(value)
+                .build()
+                .expect("Failed to build a message attribute. It's a bug.");
+            (key.clone(), value)
+        })
+        .collect();
+
+    Some(attributes)
+}
+
+/// Returns how many bytes the message attributes add to the size of every message.
+/// SQS counts the name, the data type and the value of each attribute towards the message size limit.
+fn attributes_size(attributes: &[(String, String)]) -> usize {
+    attributes
+        .iter()
+        .map(|(key, value)| key.len() + "String".len() + value.len())
+        .sum()
+}
+
+/// Returns the max size of the message body that fits into SQS with the attributes of the given size.
+fn max_body_size(attributes_len: usize) -> usize {
+    MAX_MESSAGE_SIZE.saturating_sub(SIZE_SAFETY_MARGIN + attributes_len)
+}
+
+/// How many sizes of the dropped responses are kept
+const MAX_DROPPED_SIZES: usize = 10;
+
+/// Stats of the responses dropped because they were too big for SQS even after compression.
+/// They are served via `GET /runtime/dropped`.
+pub(crate) struct DroppedResponses {
+    /// The number of responses dropped since the start or the last reset
+    pub count: u64,
+    /// Sizes of the most recently dropped responses after compression, oldest first
+    pub last_sizes: VecDeque<usize>,
+}
+
+/// Dropped responses since the emulator started or the stats were reset.
+static DROPPED_RESPONSES: Mutex<DroppedResponses> = Mutex::new(DroppedResponses {
+    count: 0,
+    last_sizes: VecDeque::new(),
+});
+
+/// Adds a dropped response to the stats.
+fn record_dropped(size: usize) {
+    if let Ok(mut dropped) = DROPPED_RESPONSES.lock() {
+        dropped.count += 1;
+        if dropped.last_sizes.len() == MAX_DROPPED_SIZES {
+            dropped.last_sizes.pop_front();
+        }
+        dropped.last_sizes.push_back(size);
+    }
+}
+
+/// Returns the stats of the dropped responses as JSON, e.g. `{"count":2,"last_sizes":[300000,280000]}`.
+/// Resets the stats after reading them if `reset` is true.
+pub(crate) fn dropped_responses(reset: bool) -> String {
+    let mut dropped = match DROPPED_RESPONSES.lock() {
+        Ok(v) => v,
+        Err(_) => panic!("DROPPED_RESPONSES lock is poisoned. It's a bug."),
     };
 
-    info!("Response sent and request deleted from the queue");
+    let stats = serde_json::json!({ "count": dropped.count, "last_sizes": dropped.last_sizes }).to_string();
+
+    if reset {
+        dropped.count = 0;
+        dropped.last_sizes.clear();
+    }
+
+    stats
+}
+
+/// The response as it is sent to SQS with the stats on how it was compressed.
+struct CompressedOutput<'a> {
+    /// The original response or its compressed and encoded version
+    body: Cow<'a, str>,
+    /// True if the body was compressed and encoded
+    was_compressed: bool,
+    /// The length of the original response in bytes
+    original_len: usize,
+    /// The length of the body in bytes
+    encoded_len: usize,
+}
+
+impl CompressedOutput<'_> {
+    /// Returns the encoded length as a fraction of the original length, e.g. 0.25 for a 4x compression.
+    fn ratio(&self) -> f64 {
+        if self.original_len == 0 {
+            return 1.0;
+        }
+
+        self.encoded_len as f64 / self.original_len as f64
+    }
 }
 
-/// Compresses and encodes the output as Base58 if the message is larger than what is
-/// allowed in SQS (262,144 bytes)
-fn compress_output(response: String) -> String {
+/// Compresses and encodes the output as Base58 or base64url if the message is not shorter than `max_len`,
+/// which is what is allowed in SQS (262,144 bytes) less the attributes and the safety margin
+fn compress_output(response: &str, encoding: Encoding, max_len: usize) -> CompressedOutput<'_> {
+    let original_len = response.len();
+
     // is it small enough to fit in?
-    if response.len() < 262144 {
-        return response;
+    if original_len < max_len {
+        return CompressedOutput {
+            body: Cow::Borrowed(response),
+            was_compressed: false,
+            original_len,
+            encoded_len: original_len,
+        };
     }
 
     info!(
-        "Message size: {}B, max allowed: 262144B. Compressing...",
-        response.len()
+        "Message size: {}B, max allowed: {}B. Compressing...",
+        response.len(),
+        max_len
     );
 
-    // try to decompress the body
-    let mut gzipper = GzEncoder::new(response.as_bytes(), Compression::fast());
-    let mut gzipped: Vec<u8> = Vec::new();
-    let compressed_len = match gzipper.read_to_end(&mut gzipped) {
+    let response = match codec::compress(response, encoding) {
         Ok(v) => v,
         Err(e) => {
             // this may not be the best option - returning an error may be more appropriate
@@ -236,10 +1157,215 @@ fn compress_output(response: String) -> String {
         }
     };
 
-    // encode to base58
-    let response = bs58::encode(&gzipped).into_string();
+    info!("Compressed and encoded: {}", response.len());
 
-    info!("Compressed: {}, encoded: {}", compressed_len, response.len());
+    CompressedOutput {
+        encoded_len: response.len(),
+        body: Cow::Owned(response),
+        was_compressed: true,
+        original_len,
+    }
+}
 
-    response
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn duplicates_are_detected_within_the_window() {
+        let mut seen = VecDeque::new();
+        let now = Instant::now();
+
+        assert!(!is_duplicate(&mut seen, "dedup-1", Some("msg-1"), now));
+        assert!(is_duplicate(
+            &mut seen,
+            "dedup-1",
+            Some("msg-2"),
+            now + Duration::from_secs(60)
+        ));
+        assert!(!is_duplicate(
+            &mut seen,
+            "dedup-2",
+            Some("msg-3"),
+            now + Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    fn redeliveries_are_not_duplicates() {
+        let mut seen = VecDeque::new();
+        let now = Instant::now();
+
+        assert!(!is_duplicate(&mut seen, "dedup-1", Some("msg-1"), now));
+        assert!(!is_duplicate(
+            &mut seen,
+            "dedup-1",
+            Some("msg-1"),
+            now + Duration::from_secs(60)
+        ));
+    }
+
+    #[test]
+    fn duplicates_expire_after_the_window() {
+        let mut seen = VecDeque::new();
+        let now = Instant::now();
+
+        assert!(!is_duplicate(&mut seen, "dedup-1", Some("msg-1"), now));
+        let later = now + DEDUP_WINDOW + Duration::from_secs(1);
+        assert!(!is_duplicate(&mut seen, "dedup-1", Some("msg-2"), later));
+        assert_eq!(seen.len(), 1);
+        assert!(is_duplicate(&mut seen, "dedup-1", Some("msg-3"), later));
+    }
+
+    #[test]
+    fn queues_are_matched_by_exact_name_first() {
+        let url = |name: &str| format!("https://sqs.us-east-1.amazonaws.com/512295225992/{name}");
+        let queue_urls = vec![
+            url("proxy_lambda_req_dlq"),
+            url("my_proxy_lambda_req"),
+            url("proxy_lambda_req"),
+            url("proxy_lambda_resp"),
+        ];
+
+        assert_eq!(
+            match_queue(&queue_urls, "proxy_lambda_req"),
+            Some(url("proxy_lambda_req"))
+        );
+        assert_eq!(
+            match_queue(&queue_urls, "proxy_lambda_resp"),
+            Some(url("proxy_lambda_resp"))
+        );
+
+        // a queue ending with the name is not a match, a queue starting with it is a fallback
+        let queue_urls = vec![
+            url("my_proxy_lambda_req"),
+            url("proxy_lambda_req_v3"),
+            url("proxy_lambda_req_v2"),
+        ];
+        assert_eq!(
+            match_queue(&queue_urls, "proxy_lambda_req"),
+            Some(url("proxy_lambda_req_v2"))
+        );
+        assert_eq!(match_queue(&queue_urls[..1], "proxy_lambda_req"), None);
+    }
+
+    #[test]
+    fn batches_are_limited_by_count_and_size() {
+        let mut buffer = (0..12)
+            .map(|v| (format!("response {v}"), None))
+            .collect::<VecDeque<(String, Option<String>)>>();
+
+        let batch = take_batch(&mut buffer, 1000);
+        assert_eq!(batch.len(), MAX_BATCH_ENTRIES);
+        assert_eq!(batch[0].0, "response 0");
+        assert_eq!(buffer.len(), 2);
+
+        let mut buffer = VecDeque::from([
+            ("a".repeat(600), None),
+            ("b".repeat(600), Some("handle".to_owned())),
+            ("c".repeat(300), None),
+        ]);
+        assert_eq!(take_batch(&mut buffer, 1000).len(), 1);
+        assert_eq!(take_batch(&mut buffer, 1000).len(), 2);
+        assert!(buffer.is_empty());
+
+        // a response too big for SQS is still taken, so that the buffer moves on
+        let mut buffer = VecDeque::from([("x".repeat(5000), None), ("y".to_owned(), None)]);
+        assert_eq!(take_batch(&mut buffer, 1000).len(), 1);
+    }
+
+    /// Returns a random JSON document that serializes to more than `min_len` bytes.
+    /// The values are drawn from small sets, so the document compresses well like real payloads do
+    /// and the Base58 encoding of the compressed document does not take minutes in debug builds.
+    fn random_json(rng: &mut StdRng, min_len: usize) -> String {
+        const WORDS: [&str; 8] = [
+            "lambda",
+            "event",
+            "ünïcödé",
+            "\"quoted\"",
+            "line\nbreak",
+            "{}",
+            "[]",
+            "🦀",
+        ];
+
+        let mut items = Vec::new();
+        let mut len = 0;
+        while len < min_len {
+            let item = json!({
+                "id": rng.gen_range(0..100),
+                "ok": rng.gen::<bool>(),
+                "text": WORDS[rng.gen_range(0..WORDS.len())].repeat(rng.gen_range(1..50)),
+            });
+            len += item.to_string().len();
+            items.push(item);
+        }
+
+        Value::Array(items).to_string()
+    }
+
+    #[test]
+    fn large_payloads_round_trip() {
+        for seed in 0..3 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let min_len = MAX_MESSAGE_SIZE + rng.gen_range(0..10_000);
+            let response = random_json(&mut rng, min_len);
+
+            for encoding in [Encoding::Base58, Encoding::Base64Url] {
+                let output = compress_output(&response, encoding, max_body_size(0));
+                assert!(output.was_compressed, "seed {seed}, {encoding:?}");
+                assert_eq!(output.encoded_len, output.body.len(), "seed {seed}, {encoding:?}");
+                assert!(codec::is_compressed(&output.body), "seed {seed}, {encoding:?}");
+                assert_eq!(
+                    codec::decompress(&output.body).unwrap(),
+                    response,
+                    "seed {seed}, {encoding:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn small_payloads_pass_through() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for response in [
+            random_json(&mut rng, 10_000),
+            r#"{"a":1}"#.to_owned(),
+            "[1,2,3]".to_owned(),
+            r#""text""#.to_owned(),
+            "42".to_owned(),
+            "null".to_owned(),
+        ] {
+            let output = compress_output(&response, Encoding::Base64Url, max_body_size(0));
+            assert!(!output.was_compressed);
+            assert!(!codec::is_compressed(&output.body));
+            assert_eq!(output.body, response);
+        }
+    }
+
+    #[test]
+    fn attributes_reduce_max_body_size() {
+        let attributes = vec![
+            ("env".to_owned(), "local".to_owned()),
+            ("source".to_owned(), "debugger".to_owned()),
+        ];
+        let attributes_len = attributes_size(&attributes);
+        assert_eq!(attributes_len, 3 + 6 + 5 + 6 + 6 + 8);
+
+        let max_len = max_body_size(attributes_len);
+        assert_eq!(max_len, MAX_MESSAGE_SIZE - SIZE_SAFETY_MARGIN - attributes_len);
+
+        // a response that fits without the attributes has to be compressed with them
+        let response = "a".repeat(max_len);
+        assert!(response.len() < max_body_size(0));
+        let output = compress_output(&response, Encoding::Base64Url, max_len);
+        assert!(output.was_compressed);
+        assert!(output.encoded_len < max_len);
+        assert_eq!(codec::decompress(&output.body).unwrap(), response);
+
+        assert_eq!(max_body_size(MAX_MESSAGE_SIZE), 0);
+    }
 }
@@ -0,0 +1,52 @@
+use crate::config::var;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tracing::info;
+
+/// Loads the TLS certificate chain and the private key from the PEM files in
+/// LAMBDA_EMULATOR_TLS_CERT and LAMBDA_EMULATOR_TLS_KEY env vars.
+/// Returns None if neither is set, which means the runtime API is served over plain HTTP.
+/// Panics if only one of them is set or the files cannot be loaded.
+pub(crate) fn load_tls_config() -> Option<Arc<ServerConfig>> {
+    let cert_file = var("LAMBDA_EMULATOR_TLS_CERT").ok().filter(|v| !v.trim().is_empty());
+    let key_file = var("LAMBDA_EMULATOR_TLS_KEY").ok().filter(|v| !v.trim().is_empty());
+
+    let (cert_file, key_file) = match (cert_file, key_file) {
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        (None, None) => return None,
+        _ => panic!("Both LAMBDA_EMULATOR_TLS_CERT and LAMBDA_EMULATOR_TLS_KEY env vars must be set to enable TLS"),
+    };
+
+    let certs = match CertificateDer::pem_file_iter(&cert_file).and_then(|v| v.collect::<Result<Vec<_>, _>>()) {
+        Ok(v) if !v.is_empty() => v,
+        Ok(_) => panic!("No certificates found in {cert_file} (LAMBDA_EMULATOR_TLS_CERT)"),
+        Err(e) => panic!(
+            "Failed to read TLS certificates from {cert_file} (LAMBDA_EMULATOR_TLS_CERT)\n{:?}",
+            e
+        ),
+    };
+
+    let key = match PrivateKeyDer::from_pem_file(&key_file) {
+        Ok(v) => v,
+        Err(e) => panic!(
+            "Failed to read TLS private key from {key_file} (LAMBDA_EMULATOR_TLS_KEY)\n{:?}",
+            e
+        ),
+    };
+
+    // the provider is set explicitly because other dependencies may enable a different one
+    let config = match ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|v| v.with_no_client_auth().with_single_cert(certs, key))
+    {
+        Ok(v) => v,
+        Err(e) => panic!("Invalid TLS certificate or key in {cert_file} / {key_file}\n{e}"),
+    };
+
+    info!("TLS enabled with the certificate from {cert_file}");
+
+    Some(Arc::new(config))
+}
@@ -0,0 +1,77 @@
+use crate::config::var;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Engine, Scope, AST};
+use serde_json::Value;
+use tracing::{info, warn};
+
+/// A Rhai script that modifies the events before they are sent to the lambda.
+/// The script gets the event as `event` variable and returns the modified event, e.g.
+/// ```rhai
+/// event.password = "***";
+/// event
+/// ```
+pub(crate) struct Transform {
+    engine: Engine,
+    ast: AST,
+    /// The script file name for logging
+    file_name: String,
+}
+
+impl Transform {
+    /// Loads and compiles the script from the file named in LAMBDA_EMULATOR_TRANSFORM_SCRIPT env var, if it is set.
+    /// Panics if the script cannot be read or compiled because the user expects the events to be transformed.
+    pub(crate) fn from_env() -> Option<Self> {
+        let file_name = var("LAMBDA_EMULATOR_TRANSFORM_SCRIPT")
+            .ok()
+            .filter(|v| !v.trim().is_empty())?;
+
+        let engine = Engine::new();
+        let ast = match engine.compile_file(file_name.clone().into()) {
+            Ok(v) => v,
+            Err(e) => {
+                panic!("Failed to compile the transform script {file_name} (LAMBDA_EMULATOR_TRANSFORM_SCRIPT)\n{e}")
+            }
+        };
+
+        info!("Transforming events with {file_name}");
+
+        Some(Self { engine, ast, file_name })
+    }
+
+    /// Runs the script on the payload and returns the result as JSON.
+    /// Returns the payload as-is if it is not JSON or the script fails, so that the event is still served.
+    pub(crate) fn apply(&self, payload: &str) -> String {
+        let event: Value = match serde_json::from_str(payload) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Event not transformed: not a valid JSON\n{:?}", e);
+                return payload.to_owned();
+            }
+        };
+
+        let event = match to_dynamic(event) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Event not transformed: cannot be passed to the script\n{e}");
+                return payload.to_owned();
+            }
+        };
+
+        let mut scope = Scope::new();
+        scope.push("event", event);
+
+        let result = self
+            .engine
+            .eval_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| e.to_string())
+            .and_then(|v| from_dynamic::<Value>(&v).map_err(|e| e.to_string()));
+
+        match result {
+            Ok(v) => v.to_string(),
+            Err(e) => {
+                warn!("Event not transformed: {} failed\n{e}", self.file_name);
+                payload.to_owned()
+            }
+        }
+    }
+}
@@ -1,11 +1,27 @@
-use lambda_runtime::Context;
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+/// The error posted by the lambda in `--once` mode, returned by `Emulator::wait`
+/// wrapped into `std::io::Error` to tell it apart from the emulator errors.
+#[derive(Debug)]
+pub struct HandlerError {
+    /// E.g. `Timeout`, if the lambda posted the error in the runtime API format
+    pub error_type: Option<String>,
+    /// The error message, if the lambda posted the error in the runtime API format
+    pub error_message: Option<String>,
+    /// The error as posted by the lambda
+    pub body: String,
+}
 
-/// A local implementation of lambda_runtime::LambdaEvent<T>.
-/// It replicates LambdaEvent<Value> because we need Ser/Deser traits not implemented for LambdaEvent.
-#[derive(Deserialize, Debug, Serialize)]
-pub struct RequestPayload {
-    pub event: Value, // using Value to extract some fields and pass the rest to the runtime
-    pub ctx: Context,
+impl std::fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.error_type, &self.error_message) {
+            (None, None) => write!(f, "Handler error: {}", self.body),
+            (error_type, error_message) => write!(
+                f,
+                "Handler error: {}: {}",
+                error_type.as_deref().unwrap_or_default(),
+                error_message.as_deref().unwrap_or_default()
+            ),
+        }
+    }
 }
+
+impl std::error::Error for HandlerError {}
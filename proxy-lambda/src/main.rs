@@ -1,11 +1,11 @@
 use aws_sdk_sqs::Client as SqsClient;
-use flate2::read::GzDecoder;
 use lambda_runtime::{service_fn, Error, LambdaEvent};
-use runtime_emulator_types::RequestPayload;
+use runtime_emulator_types::{codec, ErrorPayload, RequestPayload};
 use serde_json::Value;
 use std::env::var;
-use std::io::Read;
 use std::str::FromStr;
+use std::time::Instant;
+use tokio::sync::OnceCell;
 use tracing::{debug, error, info};
 use tracing_subscriber::{filter::Directive, EnvFilter};
 
@@ -37,6 +37,16 @@ async fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// The SQS client is reused between invocations to avoid re-creating it and its TLS stack every time.
+static SQS_CLIENT: OnceCell<SqsClient> = OnceCell::const_new();
+
+/// Returns the shared SQS client, creating it on first use.
+async fn sqs_client() -> &'static SqsClient {
+    SQS_CLIENT
+        .get_or_init(|| async { SqsClient::new(&aws_config::load_from_env().await) })
+        .await
+}
+
 async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
     let (event, ctx) = event.into_parts();
 
@@ -81,7 +91,7 @@ async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
 
     debug!("ReqQ URL: {}", request_queue_url);
 
-    let client = SqsClient::new(&aws_config::load_from_env().await);
+    let client = sqs_client().await;
 
     // Sending part
     let request_payload = RequestPayload { event, ctx };
@@ -113,6 +123,9 @@ async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
     let msg_id = send_result.message_id.unwrap_or_default();
     debug!("Sent with ID: {}", msg_id);
 
+    // the round-trip time includes the queues and the time the local lambda took to process the request
+    let sent_at = Instant::now();
+
     // This proxy should wait for a response from the local lambda if there is a response queue.
     // To determine if there is a response queue the proxy checks for the env var and tries to purge it.
     // If no env var is set, the proxy tries to purge the default queue.
@@ -121,7 +134,7 @@ async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
         Ok(response_queue_url) => {
             debug!("RespQ URL from env var: {}", response_queue_url);
             // clear the response queue to avoid getting a stale message from a previously timed out request
-            purge_response_queue(&client, &response_queue_url).await?;
+            purge_response_queue(client, &response_queue_url).await?;
             response_queue_url
         }
         Err(_) => {
@@ -146,7 +159,7 @@ async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
 
             // if this call fails it may mean the queue does not exist or is misconfigured
             // take this as the signal to not wait for a response
-            if let Err(_e) = purge_response_queue(&client, &response_queue_url).await {
+            if let Err(_e) = purge_response_queue(client, &response_queue_url).await {
                 info!("No response queue is configured");
                 return Ok(Value::Null);
             };
@@ -160,13 +173,14 @@ async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
         "Waiting for a response from the local lambda via {}",
         response_queue_url
     );
+    let wait_time = response_wait_time();
     loop {
-        debug!("20s loop");
+        debug!("{wait_time}s loop");
         let resp = match client
             .receive_message()
             .max_number_of_messages(1)
             .set_queue_url(Some(response_queue_url.to_string()))
-            .set_wait_time_seconds(Some(20))
+            .set_wait_time_seconds(Some(wait_time))
             .send()
             .await
         {
@@ -218,6 +232,8 @@ async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
             }
         };
 
+        info!("Response received in {}ms since the request was sent", sent_at.elapsed().as_millis());
+
         let body = decode_maybe_binary(body)?;
 
         // delete it from the queue so it's not picked up again
@@ -236,6 +252,16 @@ async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
         };
         debug!("Message deleted");
         info!("Response from the local lambda:\r{}", body);
+        debug!("Response from the local lambda, formatted:\r{}", pretty_json(&body));
+
+        // the local lambda failed and the emulator forwarded the error
+        if let Ok(ErrorPayload { lambda_error }) = serde_json::from_str::<ErrorPayload>(&body) {
+            let error_message = match lambda_error.get("errorMessage") {
+                Some(Value::String(v)) => v.clone(),
+                _ => lambda_error.to_string(),
+            };
+            return Err(Error::from(error_message));
+        }
 
         // return the contents of the message as JSON Value
         return Ok(Value::from_str(&body)?);
@@ -245,43 +271,45 @@ async fn my_handler(event: LambdaEvent<Value>) -> Result<Value, Error> {
 /// Checks if the message is a Base58 encoded compressed text and either decodes/decompresses it
 /// or returns as-is if it's not encoded/compressed.
 fn decode_maybe_binary(body: String) -> Result<String, Error> {
-    // check for presence of { at the beginning of the doc to determine if it's JSON or Base58
-    if body.is_empty() || body.trim_start().starts_with('{') || body.trim() == "null" {
-        // looks like JSON - return as-is
+    if !codec::is_compressed(&body) {
         return Ok(body);
     }
 
     debug!("Response payload before decoding:\r{}", body);
 
-    // try to decode base58
-    let body_decoded = match bs58::decode(&body).into_vec() {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Failed to decode from maybe base58: {:?}", e);
-            return Err(Error::from("Failed to decode from maybe base58"));
+    match codec::decompress(&body) {
+        Ok(v) => {
+            info!("Decoded {} bytes of binary response", v.len());
+            Ok(v)
         }
-    };
-
-    // try to decompress the body
-    let mut decoder = GzDecoder::new(body_decoded.as_slice());
-    let mut decoded: Vec<u8> = Vec::new();
-    let len = match decoder.read_to_end(&mut decoded) {
-        Ok(v) => v,
         Err(e) => {
-            error!("Failed to decompress the payload: {:?}", e);
-            return Err(Error::from("Failed to decompress the payload"));
+            error!("Failed to decode the compressed payload: {:?}", e);
+            Err(Error::from("Failed to decode the compressed payload"))
         }
-    };
+    }
+}
 
-    info!("Decoded {} bytes of binary response", len);
+/// Returns the long polling wait time for the response queue from PROXY_LAMBDA_RESP_WAIT_SECS env var
+/// clamped to 0..20 seconds allowed by SQS, or 20 if the var is not set or is invalid.
+fn response_wait_time() -> i32 {
+    match var("PROXY_LAMBDA_RESP_WAIT_SECS") {
+        Ok(v) => match v.trim().parse::<i32>() {
+            Ok(v) => v.clamp(0, 20),
+            Err(e) => {
+                error!("Invalid PROXY_LAMBDA_RESP_WAIT_SECS value {v}: {:?}. Using 20s.", e);
+                20
+            }
+        },
+        Err(_) => 20,
+    }
+}
 
-    // return the bytes converted into a lossy unicode string or an error
-    match String::from_utf8(decoded) {
-        Ok(v) => Ok(v),
-        Err(e) => {
-            error!("Failed to convert decompressed payload to UTF8: {:?}", e);
-            Err(Error::from("Failed to convert decompressed payload to UTF8"))
-        }
+/// Formats JSON for readability in the logs or returns the text as-is if it is not JSON.
+/// Line breaks are replaced with \r to keep the entire JSON in a single CloudWatch log entry.
+fn pretty_json(text: &str) -> String {
+    match serde_json::from_str::<Value>(text).and_then(|v| serde_json::to_string_pretty(&v)) {
+        Ok(v) => v.replace('\n', "\r"),
+        Err(_) => text.to_owned(),
     }
 }
 
@@ -359,3 +387,54 @@ fn print_env_vars() {
 
     info!("AWS env vars:\r{}", env_vars.join(" ").trim());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::decode_maybe_binary;
+    use runtime_emulator_types::codec::{compress, Encoding, COMPRESSED_BASE64URL_PREFIX, COMPRESSED_PREFIX};
+
+    #[test]
+    fn json_is_returned_as_is() {
+        for body in [
+            r#"{"a":1}"#,
+            r#"  {"a":1}"#,
+            r#"[1,2,3]"#,
+            r#"[{"a":1}]"#,
+            r#""text""#,
+            "42",
+            "-1.5",
+            "true",
+            "false",
+            "null",
+            "",
+        ] {
+            assert_eq!(decode_maybe_binary(body.to_owned()).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn marked_payload_is_decoded() {
+        for body in [r#"{"a":1}"#, r#"[1,2,3]"#, r#""text""#, "42", "true", "null"] {
+            for encoding in [Encoding::Base58, Encoding::Base64Url] {
+                assert_eq!(decode_maybe_binary(compress(body, encoding).unwrap()).unwrap(), body);
+            }
+        }
+    }
+
+    #[test]
+    fn unmarked_compressed_payload_is_decoded() {
+        for body in [r#"{"a":1}"#, r#"[1,2,3]"#, r#""text""#, "42"] {
+            let unmarked = compress(body, Encoding::Base58)
+                .unwrap()
+                .split_off(COMPRESSED_PREFIX.len());
+            assert_eq!(decode_maybe_binary(unmarked).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn invalid_payload_is_an_error() {
+        assert!(decode_maybe_binary("not json 0OIl".to_owned()).is_err());
+        assert!(decode_maybe_binary([COMPRESSED_PREFIX, "0OIl"].concat()).is_err());
+        assert!(decode_maybe_binary([COMPRESSED_BASE64URL_PREFIX, "+/="].concat()).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! Compression of responses that are too big for SQS.
+//! The emulator compresses them and the proxy decompresses them before returning to the caller.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use flate2::read::{GzDecoder, GzEncoder};
+use flate2::Compression;
+use std::io::{Error, ErrorKind, Read, Result};
+
+/// Marks compressed responses in the response queue. It is followed by the Base58 encoded gzipped response.
+/// The marker cannot start a valid JSON document, so any JSON response, including arrays and scalars,
+/// is never mistaken for a compressed one.
+pub const COMPRESSED_PREFIX: &str = "B58GZ:";
+
+/// Same as COMPRESSED_PREFIX, but for the base64url encoded gzipped responses.
+pub const COMPRESSED_BASE64URL_PREFIX: &str = "B64GZ:";
+
+/// The text encoding of the compressed responses.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    /// The default encoding, understood by all proxy versions
+    #[default]
+    Base58,
+    /// URL-safe base64 without padding, which is faster and shorter than Base58
+    Base64Url,
+}
+
+/// Gzips the text and encodes it with the marker of the encoding in front.
+pub fn compress(text: &str, encoding: Encoding) -> Result<String> {
+    let mut gzipped: Vec<u8> = Vec::new();
+    GzEncoder::new(text.as_bytes(), Compression::fast()).read_to_end(&mut gzipped)?;
+
+    let compressed = match encoding {
+        Encoding::Base58 => [COMPRESSED_PREFIX, &bs58::encode(&gzipped).into_string()].concat(),
+        Encoding::Base64Url => [COMPRESSED_BASE64URL_PREFIX, &URL_SAFE_NO_PAD.encode(&gzipped)].concat(),
+    };
+
+    Ok(compressed)
+}
+
+/// Returns true if the body should be decompressed.
+/// Bodies without a marker that are not valid JSON are considered compressed
+/// for compatibility with older emulator versions that did not add the marker.
+pub fn is_compressed(body: &str) -> bool {
+    body.starts_with(COMPRESSED_PREFIX)
+        || body.starts_with(COMPRESSED_BASE64URL_PREFIX)
+        || !(body.is_empty() || serde_json::from_str::<serde::de::IgnoredAny>(body).is_ok())
+}
+
+/// Decodes and decompresses the output of `compress`. Bodies without a marker are decoded as Base58.
+pub fn decompress(body: &str) -> Result<String> {
+    let gzipped = match body.strip_prefix(COMPRESSED_BASE64URL_PREFIX) {
+        Some(encoded) => URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        None => bs58::decode(body.strip_prefix(COMPRESSED_PREFIX).unwrap_or(body))
+            .into_vec()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+    };
+
+    let mut decoded: Vec<u8> = Vec::new();
+    GzDecoder::new(gzipped.as_slice()).read_to_end(&mut decoded)?;
+
+    String::from_utf8(decoded).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
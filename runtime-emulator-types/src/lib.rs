@@ -0,0 +1,83 @@
+//! Types and payload encoding shared by the emulator and the proxy lambda.
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use lambda_runtime::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub mod codec;
+
+/// A local implementation of lambda_runtime::LambdaEvent<T>.
+/// It replicates LambdaEvent<Value> because we need Ser/Deser traits not implemented for LambdaEvent.
+#[derive(Deserialize, Debug, Serialize)]
+pub struct RequestPayload {
+    pub event: Value, // using Value to extract some fields and pass the rest to the runtime
+    pub ctx: Context,
+}
+
+/// A lambda error forwarded to the response queue in place of a normal response.
+/// The proxy returns it to the caller as a function error.
+#[derive(Deserialize, Debug, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorPayload {
+    /// The error as posted by the lambda, usually `{"errorType": "...", "errorMessage": "..."}`.
+    pub lambda_error: Value,
+}
+
+/// Names of the headers sent to the lambda with the next invocation.
+/// See https://docs.aws.amazon.com/lambda/latest/dg/runtimes-api.html#runtimes-api-next
+pub mod headers {
+    pub const REQUEST_ID: &str = "lambda-runtime-aws-request-id";
+    pub const DEADLINE_MS: &str = "lambda-runtime-deadline-ms";
+    pub const INVOKED_FUNCTION_ARN: &str = "lambda-runtime-invoked-function-arn";
+    pub const TRACE_ID: &str = "lambda-runtime-trace-id";
+    pub const CLIENT_CONTEXT: &str = "lambda-runtime-client-context";
+    pub const COGNITO_IDENTITY: &str = "lambda-runtime-cognito-identity";
+}
+
+/// A trace ID for invocations that were not traced by X-Ray, e.g. local payloads.
+pub const DEFAULT_TRACE_ID: &str =
+    "Root=0-00000000-000000000000000000000000;Parent=0000000000000000;Sampled=0;Lineage=00000000:0";
+
+/// The values of the runtime API headers sent to the lambda with the next invocation.
+#[derive(Debug, Clone)]
+pub struct RuntimeHeaders {
+    /// The value the lambda uses in the response URL, e.g. the SQS receipt handle.
+    pub request_id: String,
+    /// The execution deadline in milliseconds since the epoch.
+    pub deadline_ms: u64,
+    pub invoked_function_arn: String,
+    /// X-Ray trace ID. DEFAULT_TRACE_ID is used if None.
+    pub trace_id: Option<String>,
+    /// Client context as JSON, for invocations from AWS Mobile SDK.
+    pub client_context: Option<String>,
+    /// Cognito identity as JSON, for invocations from AWS Mobile SDK.
+    pub cognito_identity: Option<String>,
+}
+
+impl RuntimeHeaders {
+    /// Converts the values into HTTP headers.
+    /// Panics if any of the values is not a valid header value.
+    pub fn to_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        let mut insert = |name: &'static str, value: &str| {
+            let value = HeaderValue::from_bytes(value.as_bytes())
+                .unwrap_or_else(|e| panic!("Invalid value for {name} header: {value}\n{e}"));
+            headers.insert(HeaderName::from_static(name), value);
+        };
+
+        insert(headers::REQUEST_ID, &self.request_id);
+        insert(headers::DEADLINE_MS, &self.deadline_ms.to_string());
+        insert(headers::INVOKED_FUNCTION_ARN, &self.invoked_function_arn);
+        insert(headers::TRACE_ID, self.trace_id.as_deref().unwrap_or(DEFAULT_TRACE_ID));
+        if let Some(client_context) = &self.client_context {
+            insert(headers::CLIENT_CONTEXT, client_context);
+        }
+        if let Some(cognito_identity) = &self.cognito_identity {
+            insert(headers::COGNITO_IDENTITY, cognito_identity);
+        }
+
+        headers
+    }
+}